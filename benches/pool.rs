@@ -0,0 +1,114 @@
+//! Compares running every worker as its own task with running them in a
+//! `Pool`: the memory a service of many small defs holds once loaded, and
+//! how fast writes to the var they all read propagate.
+//!
+//! `cargo bench --bench pool`, sized by `POOL_BENCH_DEFS` (default 2000)
+//! and `POOL_BENCH_WRITES` (default 200).
+
+use distr_intrp::backend::pool::Pool;
+use distr_intrp::Runtime;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+/* Counts live heap bytes, which is where tasks and channels end up */
+struct Counting;
+
+static LIVE: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        LIVE.fetch_add(layout.size(), Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        LIVE.fetch_sub(layout.size(), Ordering::Relaxed);
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: Counting = Counting;
+
+fn env_or(name: &str, default: usize) -> usize {
+    std::env::var(name)
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(default)
+}
+
+/// One var read by `defs` defs, each also read by the next one, so that a
+/// write reaches every def along a long chain as well as directly.
+fn program(defs: usize) -> String {
+    let mut src = String::from("service s { var x = 0 def d0 = x + 1 ");
+    for i in 1..defs {
+        src.push_str(&format!("def d{} = d{} + x ", i, i - 1));
+    }
+    src.push('}');
+    src
+}
+
+struct Report {
+    bytes: usize,
+    writes_per_sec: f64,
+}
+
+async fn measure(src: &str, writes: usize, shards: Option<usize>) -> Report {
+    let before = LIVE.load(Ordering::Relaxed);
+    let rt = match shards {
+        Some(shards) => Runtime::load_pooled(src, Pool::new(shards)).await,
+        None => Runtime::load(src).await,
+    }
+    .unwrap();
+    let bytes = LIVE.load(Ordering::Relaxed).saturating_sub(before);
+
+    let service = rt.service("s").unwrap().clone();
+    let start = Instant::now();
+    for i in 1..=writes {
+        rt.run("s", &format!("action {{ x = {} }}", i))
+            .await
+            .unwrap();
+    }
+    service.await_quiescent().await;
+    let elapsed = start.elapsed().max(Duration::from_micros(1));
+    Report {
+        bytes,
+        writes_per_sec: writes as f64 / elapsed.as_secs_f64(),
+    }
+}
+
+/* Each mode gets a tokio runtime of its own, shut down before the next one
+ * starts, so that neither sees the other's memory come and go. */
+fn run(src: &str, writes: usize, shards: Option<usize>) -> Report {
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    let report = runtime.block_on(measure(src, writes, shards));
+    runtime.shutdown_timeout(Duration::from_secs(5));
+    report
+}
+
+fn main() {
+    let defs = env_or("POOL_BENCH_DEFS", 2000);
+    let writes = env_or("POOL_BENCH_WRITES", 200);
+    let shards = std::thread::available_parallelism().map_or(4, |n| n.get());
+    let src = program(defs);
+
+    println!("{} defs, {} writes", defs, writes);
+    let tasks = run(&src, writes, None);
+    let pooled = run(&src, writes, Some(shards));
+    for (mode, report) in [
+        (String::from("task per worker"), tasks),
+        (format!("pool of {}", shards), pooled),
+    ] {
+        println!(
+            "{:<16} {:>10} KiB {:>8} B/def {:>10.1} writes/s",
+            mode,
+            report.bytes / 1024,
+            report.bytes / defs.max(1),
+            report.writes_per_sec,
+        );
+    }
+}
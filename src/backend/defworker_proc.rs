@@ -1,8 +1,335 @@
-use tokio::sync::mpsc;
+use crate::backend::dependency;
+use crate::backend::message::{self, Message};
+use crate::backend::worker::{self, Remains, Worker, WorkerCore};
+use crate::frontend::meerast;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::mem;
+use tracing::info;
 
-pub async fn defworker_proc(mut receive_channel: mpsc::Receiver<i32>) {
-    println!("enter defwroker");
-    while let Some(val) = receive_channel.recv().await {
-        println!("got {}", val);
+/// A def. It recomputes its expression from a replica of its predecessors'
+/// values, one round at a time, and cannot be written to.
+pub struct DefWorker {
+    pub core: WorkerCore,
+    pub def_expr: Option<meerast::Expr>, /* `None` until `InitDef` */
+    pub replica: HashMap<String, message::Val>,
+    pub preds: HashSet<String>, /* Names read by `def_expr` */
+    pub pending_rounds: BTreeMap<u64, PendingRound>,
+    pub stale: bool, /* `def_expr` is new and has not been computed yet */
+    pub last_read: HashSet<String>, /* Preds the last computation looked at */
+    pub lazy: bool,
+    pub dormant: bool,  /* Lazy and unobserved, with an empty replica */
+    pub restored: bool, /* Restarted after a crash and not computed since */
+}
+
+/// Predecessor reports received so far for a round that has not been
+/// applied to the replica yet.
+#[derive(Debug)]
+pub struct PendingRound {
+    pub round: message::Round,
+    pub reported: HashMap<String, (Option<message::Val>, bool)>, /* value, superseded */
+}
+
+impl DefWorker {
+    pub fn new(core: WorkerCore) -> DefWorker {
+        DefWorker {
+            core,
+            def_expr: None,
+            replica: HashMap::new(),
+            preds: HashSet::new(),
+            pending_rounds: BTreeMap::new(),
+            stale: false,
+            last_read: HashSet::new(),
+            lazy: false,
+            dormant: false,
+            restored: false,
+        }
+    }
+
+    /// A def in place of one whose task panicked, from its declaration and
+    /// what the dead one left. It keeps the value and the inputs the dead
+    /// one had, and computes again once an input changes.
+    pub fn restore(
+        mut core: WorkerCore,
+        def_expr: meerast::Expr,
+        lazy: bool,
+        remains: &mut Remains,
+    ) -> DefWorker {
+        core.take_over(remains);
+        let mut preds: HashSet<String> = HashSet::new();
+        dependency::expr_dependency(&mut preds, &def_expr);
+        let mut def = DefWorker::new(core);
+        def.def_expr = Some(def_expr);
+        def.replica = mem::take(&mut remains.replica);
+        def.preds = preds;
+        def.pending_rounds = mem::take(&mut remains.pending_rounds);
+        def.last_read = mem::take(&mut remains.last_read);
+        def.lazy = lazy;
+        def.restored = true;
+        def
+    }
+
+    fn is_ready(&self, pending: &PendingRound) -> bool {
+        !self
+            .preds
+            .iter()
+            .any(|p| pending.round.affected.contains(p) && !pending.reported.contains_key(p))
+    }
+
+    /// Applies pending rounds in id order, stopping at the first one still
+    /// waiting on a predecessor. Each applied round recomputes `curr_val`
+    /// once from a replica in which every predecessor changed by that round
+    /// has reported, so successors never see a mix of old and new inputs.
+    /// If no predecessor the last computation read reported a different
+    /// value, the recompute is skipped and successors are told the value is
+    /// unchanged, which in turn spares them theirs. Values of the other
+    /// predecessors, such as the untaken branch of an `if`, are still kept
+    /// in the replica for when they are read again.
+    ///
+    /// A round is not computed at all if the round after it is ready too, or
+    /// if a predecessor has skipped it: its value would be replaced before
+    /// anyone could read it. Successors are told it is superseded, and skip
+    /// it in turn.
+    ///
+    /// A lazy def that no client or live successor observes goes dormant
+    /// instead: it drops its replica and value and ignores reports until the
+    /// manager wakes it by having its predecessors report again.
+    fn finish_rounds(&mut self) {
+        let def_expr = match &self.def_expr {
+            Some(e) => e.clone(),
+            None => return, /* Predecessors unknown until `InitDef` */
+        };
+        while let Some((_, first)) = self.pending_rounds.first_key_value() {
+            if !self.is_ready(first) {
+                break;
+            }
+            let (_, pending) = self.pending_rounds.pop_first().unwrap();
+            if self.lazy && !self.core.observed() {
+                /* Successors are dormant as well, and ignore the report */
+                self.go_dormant();
+                self.core.send_to_succs(&pending.round);
+                self.core.round_done(&pending.round);
+                continue;
+            }
+            self.dormant = false;
+            self.core.interest.set_live(true);
+            let mut superseded = self
+                .pending_rounds
+                .values()
+                .next()
+                .is_some_and(|next| self.is_ready(next));
+            let mut changed = self.stale;
+            for (pred_name, (pred_value, pred_superseded)) in pending.reported.into_iter() {
+                if pred_superseded {
+                    superseded = true;
+                    continue;
+                }
+                if self.replica.get(&pred_name) == pred_value.as_ref() {
+                    continue;
+                }
+                if self.last_read.contains(&pred_name) {
+                    changed = true;
+                }
+                match pred_value {
+                    Some(v) => self.replica.insert(pred_name, v),
+                    None => self.replica.remove(&pred_name),
+                };
+            }
+            if superseded {
+                /* Whatever changed is computed in the later round */
+                self.stale = changed;
+                self.core.metrics.skipped_coalesced();
+                self.core.send_superseded(&pending.round);
+                self.core.round_done(&pending.round);
+                continue;
+            }
+            if changed && self.preds.iter().all(|p| self.replica.contains_key(p)) {
+                let mut reads: HashSet<String> = HashSet::new();
+                self.core.computing = Some(pending.round.clone());
+                let val = worker::compute_val_reading(&def_expr, &self.replica, &mut reads);
+                self.core.computing = None;
+                self.core.set_val(val);
+                self.core.metrics.recomputed();
+                self.last_read = reads;
+                self.stale = false;
+                self.restored = false;
+            } else if !changed {
+                info!(name=%self.core.name, "defworker_proc > finish_rounds > inputs unchanged, skip recompute");
+                self.core.metrics.skipped_unchanged();
+            }
+            self.core.send_to_succs(&pending.round);
+            self.core.round_done(&pending.round);
+        }
+    }
+
+    fn go_dormant(&mut self) {
+        if !self.dormant {
+            info!(name=%self.core.name, "defworker_proc > go_dormant > no observers left");
+            self.dormant = true;
+        }
+        self.core.interest.set_live(false);
+        self.replica.clear();
+        self.last_read.clear();
+        self.core.curr_val = None;
+        self.stale = true;
+    }
+
+    fn add_report(
+        &mut self,
+        pred_name: String,
+        pred_value: Option<message::Val>,
+        round: message::Round,
+        superseded: bool,
+    ) {
+        self.pending_rounds
+            .entry(round.id)
+            .or_insert_with(|| PendingRound {
+                round,
+                reported: HashMap::new(),
+            })
+            .reported
+            .insert(pred_name, (pred_value, superseded));
+    }
+
+    /* Takes every report already waiting in the inbox, so that rounds that
+     * are ready together are applied together. Stops at anything else,
+     * which is handled next. */
+    fn drain_reports(&mut self) {
+        while let Some(msg) = self.core.inbox.try_recv() {
+            match msg {
+                Message::PredUpdatedTo {
+                    pred_name,
+                    pred_value,
+                    pred_version: _,
+                    round,
+                    superseded,
+                } => self.add_report(pred_name, pred_value, round, superseded),
+                msg => {
+                    self.core.held_back = Some(msg);
+                    break;
+                }
+            }
+        }
+    }
+}
+
+impl Worker for DefWorker {
+    fn core(&mut self) -> &mut WorkerCore {
+        &mut self.core
+    }
+
+    /// A def is pending from `InitDef` until every predecessor has reported
+    /// a value; only then is it computed, once. A restarted def is pending
+    /// until it computes again, even though it kept the value of the dead
+    /// one: its inputs may have changed since.
+    fn pending_reason(&self) -> Option<String> {
+        if self.restored {
+            return Some(String::from("restarted, computed once an input changes"));
+        }
+        if self.core.curr_val.is_some() {
+            return None;
+        }
+        if self.def_expr.is_none() {
+            return Some(String::from("not initialised yet"));
+        }
+        if self.dormant {
+            return Some(String::from("dormant until read or subscribed to"));
+        }
+        let mut missing: Vec<&String> = self
+            .preds
+            .iter()
+            .filter(|p| !self.replica.contains_key(*p))
+            .collect();
+        missing.sort();
+        if missing.is_empty() {
+            return Some(String::from("waiting for an earlier round to finish"));
+        }
+        let missing: Vec<String> = missing.iter().map(|p| format!("`{}`", p)).collect();
+        Some(format!("waiting for {}", missing.join(", ")))
+    }
+
+    #[tracing::instrument(skip(self), fields(name = %self.core.name))]
+    async fn handle_message(&mut self, msg: message::Message) {
+        info!(
+            replica=?self.replica,
+            curr_val=?self.core.curr_val,
+            def_expr=?self.def_expr,
+            msg=?msg,
+            "defworker_proc > handle_message called",
+        );
+        match msg {
+            Message::InitDef {
+                def_name,
+                def_expr: def_val,
+                code_version,
+                lazy,
+                round,
+            } => {
+                self.core.name = def_name.clone();
+                self.core.version.code = code_version;
+                self.lazy = lazy;
+                if !lazy {
+                    self.core.interest.set_live(true);
+                }
+                self.def_expr = Some(def_val.clone());
+                self.stale = true;
+                self.restored = false;
+                self.preds = HashSet::new();
+                dependency::expr_dependency(&mut self.preds, &def_val);
+                info!(name=%def_name, preds=?self.preds, "defworker_proc > InitDef > wait on preds");
+                /* Nothing is reported to a def in the round that creates it,
+                 * so this round completes as soon as earlier ones have. */
+                self.pending_rounds
+                    .entry(round.id)
+                    .or_insert_with(|| PendingRound {
+                        round,
+                        reported: HashMap::new(),
+                    });
+                self.finish_rounds();
+            }
+            Message::PredUpdatedTo {
+                pred_name,
+                pred_value,
+                pred_version: _,
+                round,
+                superseded,
+            } => {
+                self.add_report(pred_name, pred_value, round, superseded);
+                self.drain_reports();
+                self.finish_rounds();
+            }
+            Message::RetrieveVal { reply_to } => {
+                self.core
+                    .apprise(reply_to, self.pending_reason(), self.dormant)
+            }
+            msg @ (Message::InitVar { .. } | Message::WriteVar { .. }) => {
+                let reason = format!("def `{}` cannot be written", self.core.name);
+                self.core.reject(msg, reason).await;
+            }
+            msg => self.core.handle_shared(msg).await,
+        }
+    }
+
+    /// Rounds taken over from a dead def may have become ready meanwhile.
+    fn resume(&mut self) {
+        self.finish_rounds();
+    }
+
+    /// Rounds still pending here will never complete, so stop counting them
+    /// as in flight.
+    fn abandon_rounds(&mut self) {
+        let pending_rounds = std::mem::take(&mut self.pending_rounds);
+        for (_, pending) in pending_rounds.into_iter() {
+            self.core.round_done(&pending.round);
+        }
+    }
+}
+
+impl Drop for DefWorker {
+    fn drop(&mut self) {
+        self.core.checkpoint.with(|remains| {
+            remains.replica = mem::take(&mut self.replica);
+            remains.pending_rounds = mem::take(&mut self.pending_rounds);
+            remains.last_read = mem::take(&mut self.last_read);
+        });
     }
 }
@@ -9,11 +9,7 @@ pub fn decl_dependency(
     match decl {
         meerast::Decl::Import { srv_name: _ } => panic!("not yet support multi service"),
         meerast::Decl::VarDecl { name: _, val: _ } => {}
-        meerast::Decl::DefDecl {
-            name,
-            val,
-            is_pub: _,
-        } => {
+        meerast::Decl::DefDecl { name, val, .. } => {
             let mut dependency_set: HashSet<String> = HashSet::new();
             expr_dependency(&mut dependency_set, val);
             dependency_graph.insert(name.clone(), dependency_set);
@@ -106,3 +102,50 @@ pub fn check_cyclic(dependency_graph: &HashMap<String, HashSet<String>>) -> Resu
     }
     Ok(())
 }
+
+pub fn descendants(
+    succ_graph: &HashMap<String, HashSet<String>>,
+    roots: &HashSet<String>,
+) -> HashSet<String> {
+    let mut rslt: HashSet<String> = HashSet::new();
+    let mut worklist: Vec<&String> = roots.iter().collect();
+    while let Some(name) = worklist.pop() {
+        if let Some(succs) = succ_graph.get(name) {
+            for succ in succs.iter() {
+                if rslt.insert(succ.clone()) {
+                    worklist.push(succ);
+                }
+            }
+        }
+    }
+    rslt
+}
+
+pub fn topo_order(dependency_graph: &HashMap<String, HashSet<String>>) -> Vec<String> {
+    fn visit(
+        name: &str,
+        dependency_graph: &HashMap<String, HashSet<String>>,
+        visited: &mut HashSet<String>,
+        order: &mut Vec<String>,
+    ) {
+        if !visited.insert(name.to_string()) {
+            return;
+        }
+        if let Some(deps) = dependency_graph.get(name) {
+            for dep in deps.iter() {
+                if dependency_graph.contains_key(dep) {
+                    visit(dep, dependency_graph, visited, order);
+                }
+            }
+        }
+        order.push(name.to_string());
+    }
+    let mut visited: HashSet<String> = HashSet::new();
+    let mut order: Vec<String> = vec![];
+    let mut names: Vec<&String> = dependency_graph.keys().collect();
+    names.sort();
+    for name in names.into_iter() {
+        visit(name, dependency_graph, &mut visited, &mut order);
+    }
+    order
+}
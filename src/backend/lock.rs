@@ -0,0 +1,314 @@
+use crate::backend::message::Reply;
+use crate::backend::remote::LockClient;
+use crate::backend::srvmanager_proc::{ServiceHandle, BUFFER_SIZE};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::net::SocketAddr;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::{mpsc, oneshot};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum LockType {
+    RLock,
+    WLock,
+}
+
+/// Identifies a code update or action across services. Transactions are
+/// ordered by age: the one started first compares smaller, ties are broken
+/// by the random `origin` of the process that started it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct TxnId {
+    pub timestamp: u64,
+    pub origin: u64,
+}
+
+/// Hands out the `TxnId`s of one service manager. Timestamps follow the
+/// clock but always increase, even for transactions started within the same
+/// microsecond or after the clock has stepped back.
+#[derive(Debug)]
+pub struct TxnClock {
+    origin: u64,
+    last: u64,
+}
+
+impl TxnClock {
+    pub fn new(origin: u64) -> TxnClock {
+        TxnClock { origin, last: 0 }
+    }
+
+    pub fn stamp(&mut self) -> TxnId {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_micros() as u64)
+            .unwrap_or(0);
+        self.last = now.max(self.last + 1);
+        TxnId {
+            timestamp: self.last,
+            origin: self.origin,
+        }
+    }
+}
+
+impl Display for TxnId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "txn {}@{:x}", self.timestamp, self.origin)
+    }
+}
+
+/// A lock request that is passed on to the owner of a name, in this or
+/// another service.
+#[derive(Debug)]
+pub enum LockCall {
+    Lock {
+        txn: TxnId,
+        kind: LockType,
+        reply_to: Reply<()>,
+    },
+    Release {
+        txn: TxnId,
+    },
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Acquire {
+    Granted,
+    /// Every conflicting holder is younger, so the requester may wait.
+    Wait,
+    /// Some conflicting holder is older, so the requester must abort.
+    Die,
+}
+
+struct Waiter {
+    txn: TxnId,
+    name: String,
+    kind: LockType,
+    reply_to: Reply<()>,
+}
+
+/// Read/write locks on the names of one service, under the wait-die rule:
+/// an older transaction waits for younger holders, a younger one dies.
+#[derive(Default)]
+pub struct LockTable {
+    held: HashMap<String, Vec<(TxnId, LockType)>>,
+    waiting: Vec<Waiter>,
+}
+
+impl LockTable {
+    pub fn new() -> LockTable {
+        LockTable::default()
+    }
+
+    pub fn acquire(&mut self, txn: TxnId, name: &str, kind: LockType) -> Acquire {
+        let conflicting: Vec<TxnId> = self
+            .held
+            .get(name)
+            .into_iter()
+            .flatten()
+            .filter(|(holder, held_kind)| {
+                *holder != txn && (kind == LockType::WLock || *held_kind == LockType::WLock)
+            })
+            .map(|(holder, _)| *holder)
+            .collect();
+        if !conflicting.is_empty() {
+            return if conflicting.iter().all(|holder| txn < *holder) {
+                Acquire::Wait
+            } else {
+                Acquire::Die
+            };
+        }
+        let holders = self.held.entry(name.to_string()).or_default();
+        match holders.iter_mut().find(|(holder, _)| *holder == txn) {
+            Some((_, held_kind)) => {
+                if kind == LockType::WLock {
+                    *held_kind = LockType::WLock;
+                }
+            }
+            None => holders.push((txn, kind)),
+        }
+        Acquire::Granted
+    }
+
+    /// Parks a request that `acquire` answered with `Wait`; `reply_to` is
+    /// answered once the lock is granted.
+    pub fn wait(&mut self, txn: TxnId, name: &str, kind: LockType, reply_to: Reply<()>) {
+        self.waiting.push(Waiter {
+            txn,
+            name: name.to_string(),
+            kind,
+            reply_to,
+        });
+    }
+
+    /// Acquires `name` for `txn`, answering `reply_to` right away unless the
+    /// request has to wait.
+    pub fn request(&mut self, txn: TxnId, name: &str, kind: LockType, reply_to: Reply<()>) {
+        match self.acquire(txn, name, kind) {
+            Acquire::Granted => {
+                let _ = reply_to.send(Ok(()));
+            }
+            Acquire::Wait => self.wait(txn, name, kind, reply_to),
+            Acquire::Die => {
+                let _ = reply_to.send(Err(format!(
+                    "{} dies waiting for `{}` held by an older transaction",
+                    txn, name
+                )));
+            }
+        }
+    }
+
+    /// Drops every lock and pending request of `txn` and grants what waiters
+    /// can now have.
+    pub fn release_all(&mut self, txn: TxnId) {
+        for holders in self.held.values_mut() {
+            holders.retain(|(holder, _)| *holder != txn);
+        }
+        self.held.retain(|_, holders| !holders.is_empty());
+        let waiting = std::mem::take(&mut self.waiting);
+        for waiter in waiting.into_iter() {
+            if waiter.txn == txn {
+                continue;
+            }
+            self.request(waiter.txn, &waiter.name, waiter.kind, waiter.reply_to);
+        }
+    }
+}
+
+/// The service that owns a name this one imports.
+#[derive(Debug, Clone)]
+pub enum Peer {
+    Local(ServiceHandle),
+    Remote(SocketAddr),
+}
+
+/// A lock a transaction needs outside its own service: on an imported name
+/// at the service that owns it, or on the mirror of one of our names in a
+/// service downstream.
+#[derive(Debug)]
+pub enum LockTarget {
+    Owner {
+        peer: Peer,
+        name: String,
+        kind: LockType,
+    },
+    Downstream {
+        locker: mpsc::Sender<LockCall>,
+        kind: LockType,
+    },
+}
+
+enum Held {
+    Local(ServiceHandle),
+    Remote(LockClient),
+    Downstream(mpsc::Sender<LockCall>),
+}
+
+/// Locks a transaction holds in other services.
+pub struct HeldElsewhere(Vec<Held>);
+
+impl HeldElsewhere {
+    pub async fn release(self, txn: TxnId) {
+        for held in self.0.into_iter() {
+            match held {
+                Held::Local(handle) => handle.release(txn).await,
+                Held::Remote(client) => client.release(txn).await,
+                Held::Downstream(locker) => {
+                    let _ = locker.send(LockCall::Release { txn }).await;
+                }
+            }
+        }
+    }
+}
+
+/// Acquires every target for `txn` in order. If one is refused, what has
+/// been acquired so far is released again.
+pub async fn lock_elsewhere(txn: TxnId, targets: Vec<LockTarget>) -> Result<HeldElsewhere, String> {
+    let mut held = HeldElsewhere(vec![]);
+    let mut clients: HashMap<SocketAddr, LockClient> = HashMap::new();
+    let mut result = Ok(());
+    for target in targets.into_iter() {
+        result = match target {
+            LockTarget::Owner {
+                peer: Peer::Local(handle),
+                name,
+                kind,
+            } => {
+                let locked = handle.lock(txn, &name, kind).await;
+                held.0.push(Held::Local(handle));
+                locked
+            }
+            LockTarget::Owner {
+                peer: Peer::Remote(addr),
+                name,
+                kind,
+            } => {
+                let client = match clients.get_mut(&addr) {
+                    Some(client) => client,
+                    None => match LockClient::connect(addr).await {
+                        Ok(client) => clients.entry(addr).or_insert(client),
+                        Err(err_msg) => {
+                            result = Err(err_msg);
+                            break;
+                        }
+                    },
+                };
+                client.lock(txn, &name, kind).await
+            }
+            LockTarget::Downstream { locker, kind } => {
+                let (reply_to, reply) = oneshot::channel();
+                let call = LockCall::Lock {
+                    txn,
+                    kind,
+                    reply_to,
+                };
+                match locker.send(call).await {
+                    /* The downstream service is gone, nothing to lock */
+                    Err(_) => Ok(()),
+                    Ok(()) => {
+                        let locked = reply
+                            .await
+                            .unwrap_or_else(|_| Err(String::from("downstream service went away")));
+                        held.0.push(Held::Downstream(locker));
+                        locked
+                    }
+                }
+            }
+        };
+        if result.is_err() {
+            break;
+        }
+    }
+    held.0.extend(clients.into_values().map(Held::Remote));
+    match result {
+        Ok(()) => Ok(held),
+        Err(err_msg) => {
+            held.release(txn).await;
+            Err(err_msg)
+        }
+    }
+}
+
+/// A locker that passes the lock calls it receives on to `name` in the
+/// service behind `handle`.
+pub fn forward_locks(handle: ServiceHandle, name: String) -> mpsc::Sender<LockCall> {
+    let (sndr, mut rcvr) = mpsc::channel::<LockCall>(BUFFER_SIZE);
+    tokio::spawn(async move {
+        while let Some(call) = rcvr.recv().await {
+            match call {
+                LockCall::Lock {
+                    txn,
+                    kind,
+                    reply_to,
+                } => {
+                    let handle = handle.clone();
+                    let name = name.clone();
+                    tokio::spawn(async move {
+                        let _ = reply_to.send(handle.lock(txn, &name, kind).await);
+                    });
+                }
+                LockCall::Release { txn } => handle.release(txn).await,
+            }
+        }
+    });
+    sndr
+}
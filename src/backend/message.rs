@@ -1,7 +1,18 @@
+use crate::backend::lock::{LockCall, LockType, Peer, TxnId};
+use crate::backend::metrics::MetricsSnapshot;
+use crate::backend::session::{Notification, Role, SessionId};
+use crate::backend::snapshot::Store;
+use crate::backend::srvmanager_proc::Subscription;
+use crate::backend::worker::{Addr, Interest};
 use crate::frontend::meerast;
-use tokio::sync::mpsc;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
+use tokio::sync::{mpsc, oneshot};
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum Val {
     Int(i32),
     Bool(bool),
@@ -9,29 +20,157 @@ pub enum Val {
     Lambda(meerast::Expr), /* Expr have to be Lambda */
 }
 
-#[derive(Debug, Clone)]
+/// A propagation round started by the manager. Every node in `affected`
+/// reports to each of its successors exactly once per round, so a def knows
+/// which predecessors to wait for before recomputing.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Round {
+    pub id: u64,
+    pub affected: Arc<HashSet<String>>,
+}
+
+/// `code` counts the declarations a worker has been (re)initialised with and
+/// `value` counts the changes of its current value.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Version {
+    pub code: u64,
+    pub value: u64,
+}
+
+/// Control messages between the manager and workers of one process. They
+/// carry channel handles and never leave the process; what is exchanged with
+/// other processes is a `wire::WireMessage`.
+#[derive(Debug)]
 pub enum Message {
     /* Manager to worker messages */
     InitVar {
         var_name: String,
         var_expr: meerast::Expr,
+        code_version: u64,
+        value_version: Option<u64>, /* Set when restored from a snapshot */
+        round: Round,
     },
     InitDef {
         def_name: String,
         def_expr: meerast::Expr,
+        code_version: u64,
+        lazy: bool,
+        round: Round,
+    },
+    WriteVar {
+        new_val: Val,
+        round: Round,
     },
     AddSenderToSucc {
-        sender: mpsc::Sender<Message>,
+        sender: Addr,
+        interest: Arc<Interest>, /* The successor's */
+        round: Round,
+    },
+    RemoveSenderToSucc {
+        sender: Addr,
+    },
+    Subscribe {
+        sender: mpsc::Sender<(Val, Version)>,
+    },
+    RetrieveVal {
+        reply_to: oneshot::Sender<Message>, /* Answered with `AppriseVal` */
     },
-    RetrieveVal,
     /* Worker to manager messages */
     AppriseVal {
         worker_name: String,
         worker_value: Option<Val>,
+        worker_version: Version,
+        pending: Option<String>, /* Why `worker_value` is `None` or not current */
+        dormant: bool,
+    },
+    Rejected {
+        worker_name: String,
+        reason: String, /* Why the worker could not handle a message */
     },
     /* Inter-worker messages */
     PredUpdatedTo {
         pred_name: String,
         pred_value: Option<Val>,
+        pred_version: Version,
+        round: Round,
+        /* The predecessor skipped this round for a later one it is part of
+         * as well, which will report its value */
+        superseded: bool,
+    },
+}
+
+pub type Reply<T> = oneshot::Sender<Result<T, String>>;
+
+/// Client to manager commands. Code updates are queued on R and actions on
+/// E; reads, subscriptions and lock calls are served right away.
+#[derive(Debug)]
+pub enum Command {
+    OpenSession {
+        role: Role,
+        reply_to: Reply<(SessionId, mpsc::Receiver<Notification>)>,
+    },
+    Declare {
+        session: SessionId,
+        decl: meerast::Decl,
+        reply_to: Reply<()>,
+    },
+    Update {
+        session: SessionId,
+        decl: meerast::Decl,
+        reply_to: Reply<()>,
+    },
+    Delete {
+        session: SessionId,
+        name: String,
+        cascade: bool,
+        reply_to: Reply<()>,
+    },
+    Do {
+        session: SessionId,
+        action: meerast::Expr,
+        reply_to: Reply<()>,
+    },
+    Read {
+        name: String,
+        reply_to: Reply<(Option<Val>, Version)>,
+    },
+    Subscribe {
+        name: String,
+        /* Set when the subscriber mirrors `name`, to lock the mirror too */
+        locker: Option<mpsc::Sender<LockCall>>,
+        reply_to: Reply<Subscription>,
+    },
+    RegisterMirror {
+        mirror: String,
+        peer: Peer,
+        member: String,
+        reply_to: Reply<()>,
+    },
+    Lock {
+        txn: TxnId,
+        name: String,
+        kind: LockType,
+        reply_to: Reply<()>,
+    },
+    Release {
+        txn: TxnId,
+    },
+    Metrics {
+        reply_to: oneshot::Sender<MetricsSnapshot>,
+    },
+    AwaitQuiescent {
+        reply_to: oneshot::Sender<()>, /* Answered once R and E are drained */
+    },
+    Shutdown {
+        reply_to: oneshot::Sender<()>, /* Answered once the workers have returned */
+    },
+    SeedVersions {
+        versions: HashMap<String, Version>,
+        reply_to: Reply<()>,
+    },
+    Persist {
+        store: Store,
+        srv: String,
+        reply_to: Reply<()>,
     },
 }
@@ -0,0 +1,54 @@
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Counts the work the defs of one service do and the recomputations they
+/// save. Shared by the manager and all of its workers.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    recomputes: AtomicU64,
+    unchanged: AtomicU64,
+    coalesced: AtomicU64,
+}
+
+/// A point-in-time copy of `Metrics`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MetricsSnapshot {
+    /// Rounds in which a def computed its expression.
+    pub recomputes: u64,
+    /// Rounds skipped because no input the def reads had changed.
+    pub unchanged: u64,
+    /// Rounds skipped because a later round, ready as well, superseded them.
+    pub coalesced: u64,
+}
+
+impl MetricsSnapshot {
+    pub fn saved(&self) -> u64 {
+        self.unchanged + self.coalesced
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Metrics::default()
+    }
+
+    pub fn recomputed(&self) {
+        self.recomputes.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn skipped_unchanged(&self) {
+        self.unchanged.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn skipped_coalesced(&self) {
+        self.coalesced.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        MetricsSnapshot {
+            recomputes: self.recomputes.load(Ordering::Relaxed),
+            unchanged: self.unchanged.load(Ordering::Relaxed),
+            coalesced: self.coalesced.load(Ordering::Relaxed),
+        }
+    }
+}
@@ -1,6 +1,15 @@
 pub mod defworker_proc;
 pub mod dependency;
+pub mod lock;
 pub mod message;
+pub mod metrics;
+pub mod pool;
+pub mod quiescence;
+pub mod remote;
+pub mod rwset;
+pub mod session;
+pub mod snapshot;
 pub mod srvmanager_proc;
 pub mod varworker_proc;
+pub mod wire;
 pub mod worker;
@@ -0,0 +1,259 @@
+use crate::backend::defworker_proc::DefWorker;
+use crate::backend::message::Message;
+use crate::backend::varworker_proc::VarWorker;
+use crate::backend::worker::{self, Addr, Checkpoint, Death, Remains, Worker, WorkerCore};
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::panic::{self, AssertUnwindSafe};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::sync::mpsc;
+use tracing::info;
+
+/// A fixed set of executor tasks that run workers as plain state machines
+/// instead of giving each its own task and channel. A worker lives on the
+/// shard its id falls in. Clones share the same tasks, which stop once the
+/// pool and every address into it are dropped.
+#[derive(Debug, Clone)]
+pub struct Pool {
+    shards: Arc<Vec<mpsc::UnboundedSender<ShardCall>>>,
+    next_node: Arc<AtomicU64>,
+}
+
+/// A worker run by a pool.
+pub enum Node {
+    Var(Box<VarWorker>),
+    Def(Box<DefWorker>),
+}
+
+/// Builds a worker in place of one that panicked, from what it left.
+pub type Revive = Box<dyn FnOnce(Remains) -> Node + Send>;
+
+pub enum ShardCall {
+    Spawn(u64, Node, mpsc::UnboundedSender<Death>), /* Where its death is reported */
+    Deliver(u64, Message),
+    Retire(u64), /* Every address of the node has been dropped */
+    Revive(u64, Revive),
+}
+
+/// A pooled worker's place in its shard. Dropping the last address of a
+/// worker retires it, as dropping the last sender closes a task's inbox.
+#[derive(Debug)]
+pub struct Slot {
+    node: u64,
+    shard: mpsc::UnboundedSender<ShardCall>,
+}
+
+impl Slot {
+    pub(crate) fn deliver(&self, msg: Message) -> Result<(), String> {
+        self.shard
+            .send(ShardCall::Deliver(self.node, msg))
+            .map_err(|_| String::from("shard stopped"))
+    }
+
+    /// Replaces the node, which has panicked, by the one `revive` builds.
+    /// Messages sent to it in the meantime are kept for the new one.
+    pub(crate) fn revive(&self, revive: Revive) {
+        let _ = self.shard.send(ShardCall::Revive(self.node, revive));
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        let _ = self.shard.send(ShardCall::Retire(self.node));
+    }
+}
+
+impl Pool {
+    /// Starts `shards` executor tasks, at least one.
+    pub fn new(shards: usize) -> Pool {
+        let shards = (0..shards.max(1))
+            .map(|_| {
+                let (sndr, rcvr) = mpsc::unbounded_channel();
+                tokio::spawn(run_shard(rcvr));
+                sndr
+            })
+            .collect();
+        Pool {
+            shards: Arc::new(shards),
+            next_node: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    /// Hands `node` to its shard. Its core must have been created with an
+    /// `Inbox::Queue`. If it panics, its shard drops it and reports its
+    /// death on `deaths`.
+    pub fn spawn(&self, node: Node, deaths: mpsc::UnboundedSender<Death>) -> Addr {
+        let id = self.next_node.fetch_add(1, Ordering::Relaxed);
+        let shard = self.shards[(id % self.shards.len() as u64) as usize].clone();
+        let _ = shard.send(ShardCall::Spawn(id, node, deaths));
+        Addr::Pooled(Arc::new(Slot { node: id, shard }))
+    }
+}
+
+impl Node {
+    fn core(&mut self) -> &mut WorkerCore {
+        match self {
+            Node::Var(worker) => worker.core(),
+            Node::Def(worker) => worker.core(),
+        }
+    }
+
+    /// Handles every message queued for the node, without waiting for more.
+    async fn run(&mut self) {
+        match self {
+            Node::Var(worker) => run_ready(worker.as_mut()).await,
+            Node::Def(worker) => run_ready(worker.as_mut()).await,
+        }
+    }
+
+    fn resume(&mut self) {
+        match self {
+            Node::Var(worker) => worker.resume(),
+            Node::Def(worker) => worker.resume(),
+        }
+    }
+
+    fn abandon_rounds(&mut self) {
+        match self {
+            Node::Var(worker) => worker.abandon_rounds(),
+            Node::Def(worker) => worker.abandon_rounds(),
+        }
+    }
+}
+
+async fn run_ready<W: Worker>(worker: &mut W) {
+    while let Some(msg) = worker.core().next_ready() {
+        worker.handle_message(msg).await;
+    }
+}
+
+/* Polls a future, turning a panic in it into an error, so a node that
+ * panics does not take its shard down */
+struct CatchUnwind<F>(Pin<Box<F>>);
+
+impl<F: Future> Future for CatchUnwind<F> {
+    type Output = Result<F::Output, String>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        match panic::catch_unwind(AssertUnwindSafe(|| self.0.as_mut().poll(cx))) {
+            Ok(Poll::Ready(out)) => Poll::Ready(Ok(out)),
+            Ok(Poll::Pending) => Poll::Pending,
+            Err(payload) => Poll::Ready(Err(worker::panic_reason(payload))),
+        }
+    }
+}
+
+/* A node run by a shard, and one that has panicked and waits to be
+ * revived */
+struct Hosted {
+    node: Node,
+    deaths: mpsc::UnboundedSender<Death>,
+    revived: bool, /* Goes on with what it took over before its next run */
+}
+
+struct Dead {
+    checkpoint: Arc<Checkpoint>,
+    deaths: mpsc::UnboundedSender<Death>,
+}
+
+/* Sends between pooled workers never wait, so a node that reports to
+ * another on its own shard cannot block the shard that has to receive it. */
+async fn run_shard(mut calls: mpsc::UnboundedReceiver<ShardCall>) {
+    let mut nodes: HashMap<u64, Hosted> = HashMap::new();
+    let mut dead: HashMap<u64, Dead> = HashMap::new();
+    while let Some(call) = calls.recv().await {
+        /* Queue everything that has arrived before running anyone, so that
+         * a def sees a burst of reports at once and can coalesce them. */
+        let mut ready: Vec<u64> = vec![];
+        let mut queued: HashSet<u64> = HashSet::new();
+        let mut retired: Vec<u64> = vec![];
+        let mut next = Some(call);
+        while let Some(call) = next {
+            match call {
+                ShardCall::Spawn(id, node, deaths) => {
+                    nodes.insert(
+                        id,
+                        Hosted {
+                            node,
+                            deaths,
+                            revived: false,
+                        },
+                    );
+                }
+                ShardCall::Deliver(id, msg) => {
+                    if let Some(hosted) = nodes.get_mut(&id) {
+                        hosted.node.core().inbox.push(msg);
+                        if queued.insert(id) {
+                            ready.push(id);
+                        }
+                    } else if let Some(dead) = dead.get(&id) {
+                        dead.checkpoint.with(|remains| {
+                            if let Some(inbox) = remains.inbox.as_mut() {
+                                inbox.push(msg);
+                            }
+                        });
+                    }
+                }
+                ShardCall::Retire(id) => retired.push(id),
+                ShardCall::Revive(id, revive) => {
+                    if let Some(Dead { checkpoint, deaths }) = dead.remove(&id) {
+                        let node = revive(checkpoint.take());
+                        nodes.insert(
+                            id,
+                            Hosted {
+                                node,
+                                deaths,
+                                revived: true,
+                            },
+                        );
+                        if queued.insert(id) {
+                            ready.push(id);
+                        }
+                    }
+                }
+            }
+            next = calls.try_recv().ok();
+        }
+        for id in ready.iter() {
+            let ran = match nodes.get_mut(id) {
+                Some(hosted) => {
+                    let revived = std::mem::take(&mut hosted.revived);
+                    let node = &mut hosted.node;
+                    CatchUnwind(Box::pin(async move {
+                        if revived {
+                            node.resume();
+                        }
+                        node.run().await
+                    }))
+                    .await
+                }
+                None => continue,
+            };
+            if let Err(reason) = ran {
+                /* Dropping the node leaves its remains in its checkpoint */
+                let Hosted {
+                    mut node, deaths, ..
+                } = nodes.remove(id).unwrap();
+                let name = node.core().name.clone();
+                let checkpoint = node.core().checkpoint.clone();
+                drop(node);
+                let _ = deaths.send(Death {
+                    name,
+                    checkpoint: checkpoint.clone(),
+                    reason: Some(reason),
+                });
+                dead.insert(*id, Dead { checkpoint, deaths });
+            }
+        }
+        for id in retired.iter() {
+            dead.remove(id);
+            if let Some(mut hosted) = nodes.remove(id) {
+                info!(name=%hosted.node.core().name, "pool > run_shard > retire");
+                hosted.node.abandon_rounds();
+            }
+        }
+    }
+}
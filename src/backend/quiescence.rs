@@ -0,0 +1,114 @@
+use crate::backend::message::Round;
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use tokio::sync::Notify;
+
+/* Rounds started with `begin`, by id, with how many of their workers have
+ * yet to finish them */
+type Rounds = HashMap<u64, (usize, Arc<HashSet<String>>)>;
+
+/// Counts (worker, round) pairs that have been started but not finished.
+/// The manager adds a round's affected workers when it starts the round and
+/// each worker takes itself off once it has processed the round, so a count
+/// of zero means every triggered recomputation has been done.
+///
+/// The count also serves as the credit for flow control: since sends
+/// between workers never wait, what piles up in their inboxes is bounded
+/// by holding back new rounds while the count is high.
+#[derive(Debug, Default)]
+pub struct InFlight {
+    count: AtomicUsize,
+    notify: Notify,
+    wake_below: AtomicUsize, /* Limit a `below` caller is waiting for */
+    rounds: Mutex<Rounds>,
+}
+
+impl InFlight {
+    pub fn new() -> Self {
+        InFlight::default()
+    }
+
+    pub fn start(&self, workers: usize) {
+        self.count.fetch_add(workers, Ordering::SeqCst);
+    }
+
+    /// Starts `round`, which can then be waited for on its own.
+    pub fn begin(&self, round: &Round) {
+        self.rounds()
+            .insert(round.id, (round.affected.len(), round.affected.clone()));
+        self.start(round.affected.len());
+    }
+
+    /// Takes a worker of `round` off.
+    pub fn finish(&self, round: &Round) {
+        let finished = match self.rounds().get_mut(&round.id) {
+            Some((left, _)) => {
+                *left -= 1;
+                *left == 0
+            }
+            None => false,
+        };
+        if finished {
+            self.rounds().remove(&round.id);
+            self.notify.notify_waiters();
+        }
+        self.done();
+    }
+
+    pub fn done(&self) {
+        let left = self.count.fetch_sub(1, Ordering::SeqCst) - 1;
+        if left == 0 || left < self.wake_below.load(Ordering::SeqCst) {
+            self.notify.notify_waiters();
+        }
+    }
+
+    /// Resolves once no round started with `begin` that affects any of
+    /// `names` is in flight, whatever other rounds are.
+    pub async fn settled(&self, names: &HashSet<String>) {
+        loop {
+            let notified = self.notify.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+            let busy = self
+                .rounds()
+                .values()
+                .any(|(_, affected)| !affected.is_disjoint(names));
+            if !busy {
+                return;
+            }
+            notified.await;
+        }
+    }
+
+    fn rounds(&self) -> MutexGuard<'_, Rounds> {
+        self.rounds
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    pub async fn quiescent(&self) {
+        self.below(1).await;
+    }
+
+    /// Resolves once fewer than `limit` pairs are in flight. Only one task,
+    /// the manager, waits on a limit above one at a time.
+    pub async fn below(&self, limit: usize) {
+        if limit > 1 {
+            self.wake_below.fetch_max(limit, Ordering::SeqCst);
+        }
+        loop {
+            let notified = self.notify.notified();
+            tokio::pin!(notified);
+            /* Register before checking, or a `done` in between is missed */
+            notified.as_mut().enable();
+            if self.count.load(Ordering::SeqCst) < limit {
+                break;
+            }
+            notified.await;
+        }
+        if limit > 1 {
+            self.wake_below.store(0, Ordering::SeqCst);
+        }
+    }
+}
@@ -0,0 +1,406 @@
+use crate::backend::lock::{LockCall, LockType, TxnId};
+use crate::backend::message::Reply;
+use crate::backend::srvmanager_proc::{ServiceHandle, Subscription, BUFFER_SIZE};
+use crate::backend::wire::{self, Encoding, WireMessage};
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::tcp::OwnedWriteHalf;
+use tokio::net::{TcpListener, TcpStream, ToSocketAddrs};
+use tokio::sync::{mpsc, oneshot};
+use tracing::info;
+
+/// Frames larger than this are refused rather than allocated.
+pub const MAX_FRAME_LEN: u32 = 16 * 1024 * 1024;
+
+/// Writes `msg` as one frame: a big-endian `u32` length followed by that
+/// many bytes of `wire::encode` output.
+pub async fn write_frame<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    msg: &WireMessage,
+    encoding: Encoding,
+) -> Result<(), String> {
+    let bytes = wire::encode(msg, encoding)?;
+    let len = u32::try_from(bytes.len())
+        .ok()
+        .filter(|len| *len <= MAX_FRAME_LEN)
+        .ok_or_else(|| format!("frame of {} bytes is too long", bytes.len()))?;
+    writer.write_u32(len).await.map_err(|err| err.to_string())?;
+    writer
+        .write_all(&bytes)
+        .await
+        .map_err(|err| err.to_string())?;
+    writer.flush().await.map_err(|err| err.to_string())
+}
+
+/// Reads the next frame, or `None` if the peer closed the connection
+/// between frames.
+pub async fn read_frame<R: AsyncRead + Unpin>(
+    reader: &mut R,
+) -> Result<Option<(WireMessage, Encoding)>, String> {
+    let len = match reader.read_u32().await {
+        Ok(len) => len,
+        Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(err.to_string()),
+    };
+    if len > MAX_FRAME_LEN {
+        return Err(format!("frame of {} bytes is too long", len));
+    }
+    let mut bytes = vec![0; len as usize];
+    reader
+        .read_exact(&mut bytes)
+        .await
+        .map_err(|err| err.to_string())?;
+    wire::decode(&bytes).map(Some)
+}
+
+/// Serves remote subscriptions to the public defs of the service behind
+/// `handle`, and lock requests on its names, until `listener` fails.
+pub async fn serve(handle: ServiceHandle, listener: TcpListener) -> Result<(), String> {
+    loop {
+        let (stream, peer) = listener.accept().await.map_err(|err| err.to_string())?;
+        info!(peer=%peer, "remote > serve > accepted connection");
+        tokio::spawn(serve_connection(handle.clone(), stream));
+    }
+}
+
+type Outbox = mpsc::Sender<(WireMessage, Encoding)>;
+
+/* Lock requests sent to a mirroring client and not answered yet. `None`
+ * once the connection is gone. */
+type PendingLocks = Arc<Mutex<Option<HashMap<u64, Reply<()>>>>>;
+
+fn spawn_writer(mut writer: OwnedWriteHalf) -> Outbox {
+    let (outbox, mut outbox_rcvr) = mpsc::channel::<(WireMessage, Encoding)>(BUFFER_SIZE);
+    tokio::spawn(async move {
+        while let Some((msg, encoding)) = outbox_rcvr.recv().await {
+            if write_frame(&mut writer, &msg, encoding).await.is_err() {
+                break;
+            }
+        }
+    });
+    outbox
+}
+
+/* Takes a lock for the peer and answers it on `outbox` */
+fn answer_lock_request(
+    handle: &ServiceHandle,
+    outbox: &Outbox,
+    encoding: Encoding,
+    req: u64,
+    txn: TxnId,
+    name: String,
+    kind: LockType,
+) {
+    let handle = handle.clone();
+    let outbox = outbox.clone();
+    tokio::spawn(async move {
+        let answer = match handle.lock(txn, &name, kind).await {
+            Ok(()) => WireMessage::LockGranted { req },
+            Err(reason) => WireMessage::LockAborted { req, reason },
+        };
+        let _ = outbox.send((answer, encoding)).await;
+    });
+}
+
+/* Replies go out in the encoding of the request they answer. Locks the
+ * peer took here are released when the connection closes. */
+async fn serve_connection(handle: ServiceHandle, stream: TcpStream) {
+    let (mut reader, writer) = stream.into_split();
+    let outbox = spawn_writer(writer);
+    let pending: PendingLocks = Arc::new(Mutex::new(Some(HashMap::new())));
+    let mut txns: HashSet<TxnId> = HashSet::new();
+    loop {
+        let (msg, encoding) = match read_frame(&mut reader).await {
+            Ok(Some(frame)) => frame,
+            Ok(None) => break,
+            Err(reason) => {
+                /* The peer speaks something else, tell it so in JSON */
+                let _ = outbox
+                    .send((WireMessage::Error { reason }, Encoding::Json))
+                    .await;
+                break;
+            }
+        };
+        match msg {
+            WireMessage::Subscribe { name } => match handle.subscribe(&name).await {
+                Ok(subscription) => {
+                    tokio::spawn(forward(name, subscription, outbox.clone(), encoding));
+                }
+                Err(reason) => {
+                    let _ = outbox.send((WireMessage::Error { reason }, encoding)).await;
+                }
+            },
+            WireMessage::SubscribeMirror { name } => {
+                let (locker, lock_calls) = mpsc::channel(BUFFER_SIZE);
+                match handle.subscribe_mirror(&name, locker).await {
+                    Ok(subscription) => {
+                        tokio::spawn(forward(
+                            name.clone(),
+                            subscription,
+                            outbox.clone(),
+                            encoding,
+                        ));
+                        tokio::spawn(relay_lock_calls(
+                            name,
+                            lock_calls,
+                            pending.clone(),
+                            outbox.clone(),
+                            encoding,
+                        ));
+                    }
+                    Err(reason) => {
+                        let _ = outbox.send((WireMessage::Error { reason }, encoding)).await;
+                    }
+                }
+            }
+            WireMessage::LockRequest {
+                req,
+                txn,
+                name,
+                kind,
+            } => {
+                txns.insert(txn);
+                answer_lock_request(&handle, &outbox, encoding, req, txn, name, kind);
+            }
+            WireMessage::ReleaseLocks { txn } => {
+                txns.remove(&txn);
+                handle.release(txn).await;
+            }
+            WireMessage::LockGranted { req } => {
+                if let Some(reply_to) = take_pending(&pending, req) {
+                    let _ = reply_to.send(Ok(()));
+                }
+            }
+            WireMessage::LockAborted { req, reason } => {
+                if let Some(reply_to) = take_pending(&pending, req) {
+                    let _ = reply_to.send(Err(reason));
+                }
+            }
+            msg => {
+                let reason = format!("unexpected message {:?}", msg);
+                let _ = outbox.send((WireMessage::Error { reason }, encoding)).await;
+            }
+        }
+    }
+    /* Dropping the unanswered requests fails them */
+    pending.lock().unwrap().take();
+    for txn in txns.into_iter() {
+        handle.release(txn).await;
+    }
+}
+
+fn take_pending(pending: &PendingLocks, req: u64) -> Option<Reply<()>> {
+    pending.lock().unwrap().as_mut()?.remove(&req)
+}
+
+/* Passes the host's calls on the client's mirror of `name` on to it */
+async fn relay_lock_calls(
+    name: String,
+    mut lock_calls: mpsc::Receiver<LockCall>,
+    pending: PendingLocks,
+    outbox: Outbox,
+    encoding: Encoding,
+) {
+    let mut next_req: u64 = 0;
+    while let Some(call) = lock_calls.recv().await {
+        let msg = match call {
+            LockCall::Lock {
+                txn,
+                kind,
+                reply_to,
+            } => {
+                let req = next_req;
+                next_req += 1;
+                match pending.lock().unwrap().as_mut() {
+                    Some(pending) => pending.insert(req, reply_to),
+                    None => break,
+                };
+                WireMessage::LockRequest {
+                    req,
+                    txn,
+                    name: name.clone(),
+                    kind,
+                }
+            }
+            LockCall::Release { txn } => WireMessage::ReleaseLocks { txn },
+        };
+        if outbox.send((msg, encoding)).await.is_err() {
+            break;
+        }
+    }
+}
+
+async fn forward(name: String, mut subscription: Subscription, outbox: Outbox, encoding: Encoding) {
+    while let Some((pred_value, pred_version)) = subscription.recv().await {
+        let msg = WireMessage::PredUpdatedTo {
+            pred_name: name.clone(),
+            pred_value,
+            pred_version,
+        };
+        if outbox.send((msg, encoding)).await.is_err() {
+            break;
+        }
+    }
+}
+
+/// Subscribes to the public def `name` of the service served at `addr`,
+/// using the binary encoding. The subscription ends when the connection
+/// does.
+pub async fn subscribe<A: ToSocketAddrs>(addr: A, name: &str) -> Result<Subscription, String> {
+    subscribe_with(addr, name, Encoding::Binary).await
+}
+
+pub async fn subscribe_with<A: ToSocketAddrs>(
+    addr: A,
+    name: &str,
+    encoding: Encoding,
+) -> Result<Subscription, String> {
+    let request = WireMessage::Subscribe {
+        name: name.to_string(),
+    };
+    open_subscription(addr, request, encoding, None).await
+}
+
+/// Subscribes to `name` for a local mirror of it. Lock requests the host
+/// makes on the mirror are passed on to `locker`.
+pub async fn subscribe_mirror<A: ToSocketAddrs>(
+    addr: A,
+    name: &str,
+    locker: mpsc::Sender<LockCall>,
+) -> Result<Subscription, String> {
+    let request = WireMessage::SubscribeMirror {
+        name: name.to_string(),
+    };
+    open_subscription(addr, request, Encoding::Binary, Some(locker)).await
+}
+
+async fn open_subscription<A: ToSocketAddrs>(
+    addr: A,
+    request: WireMessage,
+    encoding: Encoding,
+    locker: Option<mpsc::Sender<LockCall>>,
+) -> Result<Subscription, String> {
+    let mut stream = TcpStream::connect(addr)
+        .await
+        .map_err(|err| err.to_string())?;
+    write_frame(&mut stream, &request, encoding).await?;
+    /* The host answers with the current value or an error */
+    let first = match read_frame(&mut stream).await? {
+        Some((
+            WireMessage::PredUpdatedTo {
+                pred_value,
+                pred_version,
+                ..
+            },
+            _,
+        )) => (pred_value, pred_version),
+        Some((WireMessage::Error { reason }, _)) => return Err(reason),
+        Some((msg, _)) => return Err(format!("unexpected message {:?}", msg)),
+        None => return Err(String::from("connection closed")),
+    };
+    let (sender, receiver) = mpsc::channel(BUFFER_SIZE);
+    let _ = sender.send(first).await;
+    let (mut reader, writer) = stream.into_split();
+    let outbox = spawn_writer(writer);
+    tokio::spawn(async move {
+        let mut txns: HashSet<TxnId> = HashSet::new();
+        while let Ok(Some((msg, encoding))) = read_frame(&mut reader).await {
+            match (msg, &locker) {
+                (
+                    WireMessage::PredUpdatedTo {
+                        pred_value,
+                        pred_version,
+                        ..
+                    },
+                    _,
+                ) => {
+                    if sender.send((pred_value, pred_version)).await.is_err() {
+                        break;
+                    }
+                }
+                (WireMessage::LockRequest { req, txn, kind, .. }, Some(locker)) => {
+                    txns.insert(txn);
+                    let locker = locker.clone();
+                    let outbox = outbox.clone();
+                    tokio::spawn(async move {
+                        let (reply_to, reply) = oneshot::channel();
+                        let call = LockCall::Lock {
+                            txn,
+                            kind,
+                            reply_to,
+                        };
+                        let locked = match locker.send(call).await {
+                            Ok(()) => reply
+                                .await
+                                .unwrap_or_else(|_| Err(String::from("lock request dropped"))),
+                            Err(_) => Err(String::from("mirror is gone")),
+                        };
+                        let answer = match locked {
+                            Ok(()) => WireMessage::LockGranted { req },
+                            Err(reason) => WireMessage::LockAborted { req, reason },
+                        };
+                        let _ = outbox.send((answer, encoding)).await;
+                    });
+                }
+                (WireMessage::ReleaseLocks { txn }, Some(locker)) => {
+                    txns.remove(&txn);
+                    let _ = locker.send(LockCall::Release { txn }).await;
+                }
+                _ => break,
+            }
+        }
+        /* The host is gone, so are its transactions */
+        if let Some(locker) = locker {
+            for txn in txns.into_iter() {
+                let _ = locker.send(LockCall::Release { txn }).await;
+            }
+        }
+    });
+    Ok(Subscription::new(receiver))
+}
+
+/// A connection over which a service locks names of the service served at
+/// the other end. Its locks are released when it is dropped.
+pub struct LockClient {
+    stream: TcpStream,
+    next_req: u64,
+}
+
+impl LockClient {
+    pub async fn connect<A: ToSocketAddrs>(addr: A) -> Result<LockClient, String> {
+        let stream = TcpStream::connect(addr)
+            .await
+            .map_err(|err| err.to_string())?;
+        Ok(LockClient {
+            stream,
+            next_req: 0,
+        })
+    }
+
+    /// Locks `name` for `txn`, waiting as long as wait-die lets it. Fails if
+    /// `txn` has to die.
+    pub async fn lock(&mut self, txn: TxnId, name: &str, kind: LockType) -> Result<(), String> {
+        let req = self.next_req;
+        self.next_req += 1;
+        let request = WireMessage::LockRequest {
+            req,
+            txn,
+            name: name.to_string(),
+            kind,
+        };
+        write_frame(&mut self.stream, &request, Encoding::Binary).await?;
+        match read_frame(&mut self.stream).await? {
+            Some((WireMessage::LockGranted { req: granted }, _)) if granted == req => Ok(()),
+            Some((WireMessage::LockAborted { reason, .. }, _))
+            | Some((WireMessage::Error { reason }, _)) => Err(reason),
+            Some((msg, _)) => Err(format!("unexpected message {:?}", msg)),
+            None => Err(String::from("connection closed")),
+        }
+    }
+
+    pub async fn release(mut self, txn: TxnId) {
+        let request = WireMessage::ReleaseLocks { txn };
+        let _ = write_frame(&mut self.stream, &request, Encoding::Binary).await;
+    }
+}
@@ -0,0 +1,218 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::{backend::worker, frontend::meerast};
+
+/// The state vars an action may read and write when it runs.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RwSet {
+    pub reads: HashSet<String>,
+    pub writes: HashSet<String>,
+}
+
+/// Statically computes the read/write set of `action`.
+///
+/// `vars` names the state vars of the service and `defs` maps every def to
+/// its defining expression. `do` statements are followed through defs,
+/// lambda applications and both branches of `if`, so the result covers every
+/// var the action can touch transitively. Reading a def counts as reading
+/// every var the def depends on. The analysis is conservative: it may report
+/// more reads/writes than a particular run performs, never fewer.
+pub fn action_rw_set(
+    action: &meerast::Expr,
+    vars: &HashSet<String>,
+    defs: &HashMap<String, meerast::Expr>,
+) -> Result<RwSet, String> {
+    let mut analysis = RwAnalysis {
+        vars,
+        defs,
+        rw_set: RwSet::default(),
+        read_defs: HashSet::new(),
+        doing: vec![],
+    };
+    analysis.do_expr(action, &HashSet::new())?;
+    Ok(analysis.rw_set)
+}
+
+struct RwAnalysis<'a> {
+    vars: &'a HashSet<String>,
+    defs: &'a HashMap<String, meerast::Expr>,
+    rw_set: RwSet,
+    /* defs whose reads have already been added */
+    read_defs: HashSet<String>,
+    /* defs currently being followed by `do`, to cut recursive actions */
+    doing: Vec<String>,
+}
+
+impl<'a> RwAnalysis<'a> {
+    /// Accounts for running the action that `expr` evaluates to. `locals`
+    /// are lambda parameters in scope, which shadow service-level names.
+    fn do_expr(&mut self, expr: &meerast::Expr, locals: &HashSet<String>) -> Result<(), String> {
+        match expr {
+            meerast::Expr::Action { stmt } => {
+                let sgls = match stmt {
+                    meerast::Stmt::Stmt { sgl_stmts } => sgl_stmts,
+                };
+                for sgl in sgls.iter() {
+                    match sgl {
+                        meerast::SglStmt::Do { act } => self.do_expr(act, locals)?,
+                        meerast::SglStmt::Ass { dst, src } => {
+                            let dst_name = match dst {
+                                meerast::Expr::IdExpr { ident } => ident,
+                                _ => return Err(format!("assignment to non identifier {:?}", dst)),
+                            };
+                            if locals.contains(dst_name) || !self.vars.contains(dst_name) {
+                                return Err(format!("assignment to non var `{}`", dst_name));
+                            }
+                            self.rw_set.writes.insert(dst_name.clone());
+                            self.read_expr(src, locals)?;
+                        }
+                    }
+                }
+                Ok(())
+            }
+            meerast::Expr::IdExpr { ident } => {
+                if locals.contains(ident) {
+                    return Err(format!(
+                        "cannot resolve action bound to parameter `{}`",
+                        ident
+                    ));
+                }
+                match self.defs.get(ident) {
+                    Some(def_expr) => {
+                        if self.doing.contains(ident) {
+                            return Ok(());
+                        }
+                        self.doing.push(ident.clone());
+                        let rslt = self.do_expr(def_expr, &HashSet::new());
+                        self.doing.pop();
+                        rslt
+                    }
+                    None => Err(format!("`do` on `{}`, which is not an action def", ident)),
+                }
+            }
+            meerast::Expr::Apply { fun, args } => {
+                for arg in args.iter() {
+                    self.read_expr(arg, locals)?;
+                }
+                let (lambda, lambda_def) = self.resolve_lambda(fun, locals)?;
+                if let Some(name) = &lambda_def {
+                    if self.doing.contains(name) {
+                        return Ok(());
+                    }
+                    self.doing.push(name.clone());
+                }
+                let substed_body = worker::subst_pars_with_args(&lambda, args);
+                let rslt = self.do_expr(&substed_body, locals);
+                if lambda_def.is_some() {
+                    self.doing.pop();
+                }
+                rslt
+            }
+            meerast::Expr::IfExpr { cond, then, elze } => {
+                self.read_expr(cond, locals)?;
+                self.do_expr(then, locals)?;
+                self.do_expr(elze, locals)
+            }
+            meerast::Expr::Member {
+                srv_name: _,
+                member: _,
+            } => Err(String::from("not yet support multi service")),
+            _ => Err(format!("`do` on non action expression {:?}", expr)),
+        }
+    }
+
+    /// Resolves the function position of an application to a lambda,
+    /// returning the def it came from, if any.
+    fn resolve_lambda(
+        &mut self,
+        fun: &meerast::Expr,
+        locals: &HashSet<String>,
+    ) -> Result<(meerast::Expr, Option<String>), String> {
+        match fun {
+            meerast::Expr::Lambda { pars: _, body: _ } => Ok((fun.clone(), None)),
+            meerast::Expr::IdExpr { ident } if !locals.contains(ident) => {
+                match self.defs.get(ident) {
+                    Some(def_expr @ meerast::Expr::Lambda { pars: _, body: _ }) => {
+                        Ok((def_expr.clone(), Some(ident.clone())))
+                    }
+                    Some(def_expr) => {
+                        let (lambda, _) = self.resolve_lambda(def_expr, &HashSet::new())?;
+                        Ok((lambda, Some(ident.clone())))
+                    }
+                    None => Err(format!("cannot resolve `{}` to a function def", ident)),
+                }
+            }
+            _ => Err(format!("cannot statically resolve function {:?}", fun)),
+        }
+    }
+
+    /// Accounts for evaluating `expr` as a value.
+    fn read_expr(&mut self, expr: &meerast::Expr, locals: &HashSet<String>) -> Result<(), String> {
+        match expr {
+            meerast::Expr::IdExpr { ident } => {
+                if locals.contains(ident) {
+                    Ok(())
+                } else if self.vars.contains(ident) {
+                    self.rw_set.reads.insert(ident.clone());
+                    Ok(())
+                } else if let Some(def_expr) = self.defs.get(ident) {
+                    if self.read_defs.insert(ident.clone()) {
+                        self.read_expr(def_expr, &HashSet::new())
+                    } else {
+                        Ok(())
+                    }
+                } else {
+                    Err(format!("unbound identifier `{}`", ident))
+                }
+            }
+            meerast::Expr::IntConst { val: _ } | meerast::Expr::BoolConst { val: _ } => Ok(()),
+            meerast::Expr::Action { stmt } => {
+                /* An action read as a value may still be run later, so its
+                 * sources count as reads. Its writes only happen via `do`. */
+                let sgls = match stmt {
+                    meerast::Stmt::Stmt { sgl_stmts } => sgl_stmts,
+                };
+                for sgl in sgls.iter() {
+                    match sgl {
+                        meerast::SglStmt::Do { act } => self.read_expr(act, locals)?,
+                        meerast::SglStmt::Ass { dst: _, src } => self.read_expr(src, locals)?,
+                    }
+                }
+                Ok(())
+            }
+            meerast::Expr::Member {
+                srv_name: _,
+                member: _,
+            } => Err(String::from("not yet support multi service")),
+            meerast::Expr::Apply { fun, args } => {
+                self.read_expr(fun, locals)?;
+                for arg in args.iter() {
+                    self.read_expr(arg, locals)?;
+                }
+                Ok(())
+            }
+            meerast::Expr::BopExpr { opd1, opd2, bop: _ } => {
+                self.read_expr(opd1, locals)?;
+                self.read_expr(opd2, locals)
+            }
+            meerast::Expr::UopExpr { opd, uop: _ } => self.read_expr(opd, locals),
+            meerast::Expr::IfExpr { cond, then, elze } => {
+                self.read_expr(cond, locals)?;
+                self.read_expr(then, locals)?;
+                self.read_expr(elze, locals)
+            }
+            meerast::Expr::Lambda { pars, body } => {
+                let mut body_locals = locals.clone();
+                for par in pars.iter() {
+                    match par {
+                        meerast::Expr::IdExpr { ident } => {
+                            body_locals.insert(ident.clone());
+                        }
+                        _ => return Err(format!("non identifier parameter {:?}", par)),
+                    }
+                }
+                self.read_expr(body, &body_locals)
+            }
+        }
+    }
+}
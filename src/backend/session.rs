@@ -0,0 +1,134 @@
+use crate::backend::message::{Command, Val, Version};
+use crate::backend::srvmanager_proc::{ServiceHandle, Subscription};
+use crate::frontend::meerast;
+use std::fmt::Display;
+use tokio::sync::mpsc;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Role {
+    Developer,
+    User,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct SessionId(pub u64);
+
+impl Display for SessionId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "session {}", self.0)
+    }
+}
+
+/// Sent to a session when something it submitted did not go through after
+/// it had been queued, and to developers when a worker has died.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Notification {
+    UpdateAborted {
+        name: String,
+        reason: String,
+    },
+    ActionAborted {
+        action: meerast::Expr,
+        reason: String,
+    },
+    WorkerRestarted {
+        name: String,
+        reason: String, /* What the worker panicked with */
+    },
+}
+
+/// A developer or user connected to a service. Every command sent through a
+/// session is attributed to it; only developers may change the code.
+#[derive(Debug)]
+pub struct Session {
+    id: SessionId,
+    role: Role,
+    handle: ServiceHandle,
+    notifications: mpsc::Receiver<Notification>,
+}
+
+impl Session {
+    pub(crate) fn new(
+        id: SessionId,
+        role: Role,
+        handle: ServiceHandle,
+        notifications: mpsc::Receiver<Notification>,
+    ) -> Session {
+        Session {
+            id,
+            role,
+            handle,
+            notifications,
+        }
+    }
+
+    pub fn id(&self) -> SessionId {
+        self.id
+    }
+
+    pub fn role(&self) -> Role {
+        self.role
+    }
+
+    pub fn handle(&self) -> &ServiceHandle {
+        &self.handle
+    }
+
+    pub async fn declare(&self, decl: meerast::Decl) -> Result<(), String> {
+        self.handle
+            .request(|reply_to| Command::Declare {
+                session: self.id,
+                decl,
+                reply_to,
+            })
+            .await
+    }
+
+    pub async fn update(&self, decl: meerast::Decl) -> Result<(), String> {
+        self.handle
+            .request(|reply_to| Command::Update {
+                session: self.id,
+                decl,
+                reply_to,
+            })
+            .await
+    }
+
+    pub async fn delete(&self, name: &str, cascade: bool) -> Result<(), String> {
+        self.handle
+            .request(|reply_to| Command::Delete {
+                session: self.id,
+                name: name.to_string(),
+                cascade,
+                reply_to,
+            })
+            .await
+    }
+
+    pub async fn run_action(&self, action: meerast::Expr) -> Result<(), String> {
+        self.handle
+            .request(|reply_to| Command::Do {
+                session: self.id,
+                action,
+                reply_to,
+            })
+            .await
+    }
+
+    pub async fn read(&self, name: &str) -> Result<(Option<Val>, Version), String> {
+        self.handle.read(name).await
+    }
+
+    pub async fn subscribe(&self, name: &str) -> Result<Subscription, String> {
+        self.handle.subscribe(name).await
+    }
+
+    pub async fn next_notification(&mut self) -> Option<Notification> {
+        self.notifications.recv().await
+    }
+
+    /// The next notification if one has arrived already.
+    pub fn try_notification(&mut self) -> Option<Notification> {
+        self.notifications.try_recv().ok()
+    }
+}
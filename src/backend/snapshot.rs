@@ -0,0 +1,142 @@
+use crate::backend::message::{Val, Version};
+use crate::frontend::meerast;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use tokio::sync::{mpsc, oneshot};
+use tracing::info;
+
+/// Bumped on every change to `Snapshot` that older snapshots do not fit.
+pub const SNAPSHOT_VERSION: u16 = 1;
+
+/// What is saved of a running program, as JSON: the source it was loaded
+/// from and, for each of its services, what the last commit left.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub version: u16,
+    pub program: String,
+    pub services: BTreeMap<String, ServiceSnapshot>,
+}
+
+/// The declarations of a service as they stand after its code updates,
+/// with their code versions, and the value of each of its vars. Mirrors of
+/// members of other services are left out, their hosts keep those.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ServiceSnapshot {
+    pub decls: BTreeMap<String, (meerast::Decl, u64)>,
+    pub vars: BTreeMap<String, (Val, Version)>,
+}
+
+impl Snapshot {
+    pub fn new(program: &str) -> Snapshot {
+        Snapshot {
+            version: SNAPSHOT_VERSION,
+            program: program.to_string(),
+            services: BTreeMap::new(),
+        }
+    }
+
+    pub async fn read(path: &Path) -> Result<Snapshot, String> {
+        let bytes = tokio::fs::read(path)
+            .await
+            .map_err(|err| format!("cannot read snapshot {}: {}", path.display(), err))?;
+        let snapshot: Snapshot = serde_json::from_slice(&bytes)
+            .map_err(|err| format!("cannot decode snapshot {}: {}", path.display(), err))?;
+        if snapshot.version != SNAPSHOT_VERSION {
+            return Err(format!(
+                "snapshot version {} is not supported, expected {}",
+                snapshot.version, SNAPSHOT_VERSION
+            ));
+        }
+        Ok(snapshot)
+    }
+
+    /// Writes to a file next to `path` first and then moves it over, so a
+    /// crash while writing leaves the previous snapshot intact.
+    pub async fn write(&self, path: &Path) -> Result<(), String> {
+        let bytes = serde_json::to_vec_pretty(self).map_err(|err| err.to_string())?;
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        let tmp = PathBuf::from(tmp);
+        tokio::fs::write(&tmp, bytes)
+            .await
+            .map_err(|err| format!("cannot write snapshot {}: {}", tmp.display(), err))?;
+        tokio::fs::rename(&tmp, path)
+            .await
+            .map_err(|err| format!("cannot write snapshot {}: {}", path.display(), err))
+    }
+}
+
+/// Where services save their snapshots. The file is written by a task of
+/// its own, which writes saves that arrive together once, so managers never
+/// wait for the disk. Clones share the task, which stops once every clone
+/// is dropped.
+#[derive(Debug, Clone)]
+pub struct Store {
+    calls: mpsc::UnboundedSender<StoreCall>,
+}
+
+enum StoreCall {
+    Save(String, ServiceSnapshot),
+    Flush(oneshot::Sender<Result<(), String>>),
+}
+
+impl Store {
+    /// Saves to `path`, starting from `snapshot`.
+    pub fn open(path: &Path, snapshot: Snapshot) -> Store {
+        let (sndr, rcvr) = mpsc::unbounded_channel();
+        tokio::spawn(run_store(path.to_path_buf(), snapshot, rcvr));
+        Store { calls: sndr }
+    }
+
+    /// Replaces what is saved of `srv`.
+    pub fn save(&self, srv: &str, saved: ServiceSnapshot) {
+        let _ = self.calls.send(StoreCall::Save(srv.to_string(), saved));
+    }
+
+    /// Resolves once every save made so far is on disk.
+    pub async fn flush(&self) -> Result<(), String> {
+        let (reply_to, reply) = oneshot::channel();
+        self.calls
+            .send(StoreCall::Flush(reply_to))
+            .map_err(|_| String::from("snapshot store is gone"))?;
+        reply
+            .await
+            .map_err(|_| String::from("snapshot store dropped the flush"))?
+    }
+}
+
+async fn run_store(
+    path: PathBuf,
+    mut snapshot: Snapshot,
+    mut calls: mpsc::UnboundedReceiver<StoreCall>,
+) {
+    let mut written = Ok(());
+    while let Some(call) = calls.recv().await {
+        let mut dirty = false;
+        let mut flushes: Vec<oneshot::Sender<Result<(), String>>> = vec![];
+        let mut next = Some(call);
+        while let Some(call) = next {
+            match call {
+                StoreCall::Save(srv, saved) => {
+                    snapshot.services.insert(srv, saved);
+                    dirty = true;
+                }
+                StoreCall::Flush(reply_to) => flushes.push(reply_to),
+            }
+            next = calls.try_recv().ok();
+        }
+        if dirty {
+            written = snapshot.write(&path).await;
+            match &written {
+                Ok(()) => info!(path=%path.display(), "snapshot > run_store > written"),
+                Err(reason) => {
+                    tracing::error!(reason=%reason, "snapshot > run_store > write fails")
+                }
+            }
+        }
+        for reply_to in flushes.into_iter() {
+            let _ = reply_to.send(written.clone());
+        }
+    }
+}
@@ -1,33 +1,31 @@
-use crate::backend::message::{Message, Val};
-use crate::{backend::worker::Worker, frontend::meerast, frontend::typecheck};
-use inline_colorization::*;
-use std::collections::{HashMap, HashSet};
-use tokio::sync::mpsc;
+use crate::backend::defworker_proc::DefWorker;
+use crate::backend::lock::{
+    self, Acquire, HeldElsewhere, LockCall, LockTable, LockTarget, LockType,
+};
+use crate::backend::lock::{Peer, TxnClock, TxnId};
+use crate::backend::message::{Command, Message, Reply, Round, Val, Version};
+use crate::backend::metrics::{Metrics, MetricsSnapshot};
+use crate::backend::pool::{Node, Pool, Revive};
+use crate::backend::quiescence::InFlight;
+use crate::backend::session::{Notification, Role, Session, SessionId};
+use crate::backend::snapshot::{ServiceSnapshot, Store};
+use crate::backend::varworker_proc::VarWorker;
+use crate::backend::worker::{self, Addr, Checkpoint, Death, Inbox, Interest, Worker, WorkerCore};
+use crate::backend::{dependency, rwset};
+use crate::{frontend::meerast, frontend::typecheck};
+use rand::Rng;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::{JoinHandle, JoinSet};
 use tracing::{self, info};
 
-const BUFFER_SIZE: usize = 1024;
-
-async fn run_worker(mut worker: Worker) {
-    while let Some(msg) = worker.inbox.recv().await {
-        // worker.handle_message(msg).await;
-        let _ = Worker::handle_message(
-            &worker.sender_to_manager,
-            &mut worker.senders_to_succs,
-            &mut worker.replica,
-            &mut worker.curr_val,
-            &mut worker.def_expr,
-            &mut worker.name,
-            &msg,
-        )
-        .await;
-    }
-}
+pub const BUFFER_SIZE: usize = 1024;
 
-#[derive(Debug)]
-pub enum LockType {
-    RLock,
-    WLock,
-}
+/// Worker-round pairs that may be in flight before new writes wait.
+pub const IN_FLIGHT_LIMIT: usize = 16 * BUFFER_SIZE;
 
 #[derive(Debug)]
 pub enum VarOrDef {
@@ -37,16 +35,52 @@ pub enum VarOrDef {
 
 pub struct ServiceManager {
     // channels
-    pub worker_inboxes: HashMap<String, mpsc::Sender<Message>>,
-    pub sender_to_manager: mpsc::Sender<Message>,
-    pub receiver_from_workers: mpsc::Receiver<Message>,
+    worker_inboxes: HashMap<String, Addr>,
+    pool: Option<Pool>, /* `None` runs each worker as its own task */
+    sender_to_manager: mpsc::Sender<Message>,
+    // taken by `spawn`, which reports what workers reject
+    receiver_from_workers: Option<mpsc::Receiver<Message>>,
+    // supervision: workers run as tasks of their own are watched, those
+    // run by a pool are reported dead by their shard
+    watchers: JoinSet<Death>,
+    deaths: (mpsc::UnboundedSender<Death>, mpsc::UnboundedReceiver<Death>),
+    checkpoints: HashMap<String, Arc<Checkpoint>>,
     // locks
-    pub locks: HashMap<String, Option<LockType>>,
+    locks: LockTable,
+    txns: TxnClock, /* stamps our transactions apart from other services' */
+    upstream: HashMap<String, (Peer, String)>, /* mirror to its owner and member */
+    downstream: HashMap<String, Vec<mpsc::Sender<LockCall>>>, /* pub def to lockers of its mirrors */
     // typing env
-    pub typenv: HashMap<String, Option<typecheck::Type>>,
-    pub var_or_def_env: HashMap<String, VarOrDef>,
+    typenv: HashMap<String, Option<typecheck::Type>>,
+    var_or_def_env: HashMap<String, VarOrDef>,
     // dependency graph
-    pub dependgraph: HashMap<String, HashSet<String>>,
+    dependgraph: HashMap<String, HashSet<String>>,
+    // propagation
+    next_round_id: u64,
+    in_flight: Arc<InFlight>,
+    metrics: Arc<Metrics>,
+    interests: HashMap<String, Arc<Interest>>, /* shared with each worker's predecessors */
+    // code versions
+    decls: HashMap<String, meerast::Decl>,
+    code_versions: HashMap<String, u64>,
+    // sessions
+    developers: HashMap<SessionId, mpsc::Sender<Notification>>,
+    users: HashMap<SessionId, mpsc::Sender<Notification>>,
+    next_session_id: u64,
+    // persistence
+    store: Option<(Store, String)>, /* and the service name saved under */
+    seeds: HashMap<String, Version>, /* Versions restored from a snapshot */
+}
+
+pub struct QueuedAction {
+    pub action: meerast::Expr,
+    pub captured_versions: HashMap<String, u64>,
+}
+
+impl Default for ServiceManager {
+    fn default() -> Self {
+        ServiceManager::new()
+    }
 }
 
 impl ServiceManager {
@@ -54,114 +88,1764 @@ impl ServiceManager {
         let (sndr, rcvr) = mpsc::channel(BUFFER_SIZE);
         ServiceManager {
             worker_inboxes: HashMap::new(),
+            pool: None,
             sender_to_manager: sndr,
-            receiver_from_workers: rcvr,
-            locks: HashMap::new(),
+            receiver_from_workers: Some(rcvr),
+            watchers: JoinSet::new(),
+            deaths: mpsc::unbounded_channel(),
+            checkpoints: HashMap::new(),
+            locks: LockTable::new(),
+            txns: TxnClock::new(rand::random()),
+            upstream: HashMap::new(),
+            downstream: HashMap::new(),
             typenv: HashMap::new(),
             var_or_def_env: HashMap::new(),
             dependgraph: HashMap::new(),
+            next_round_id: 0,
+            in_flight: Arc::new(InFlight::new()),
+            metrics: Arc::new(Metrics::new()),
+            interests: HashMap::new(),
+            decls: HashMap::new(),
+            code_versions: HashMap::new(),
+            developers: HashMap::new(),
+            users: HashMap::new(),
+            next_session_id: 0,
+            store: None,
+            seeds: HashMap::new(),
         }
     }
 
-    #[tracing::instrument(skip(worker_inboxes, locks, /* sender_to_manager, */))]
-    pub fn create_worker(
-        name: &str,
-        workertype: VarOrDef,
-        sender_to_manager: mpsc::Sender<Message>,
-        subscribers: &HashSet<String>,
-
-        worker_inboxes: &mut HashMap<String, mpsc::Sender<Message>>,
-        locks: &mut HashMap<String, Option<LockType>>,
-        typenv: &mut HashMap<String, Option<typecheck::Type>>,
-        var_or_def_env: &mut HashMap<String, VarOrDef>,
-        dependgraph: &mut HashMap<String, HashSet<String>>,
-    ) {
-        tracing::info!("srvmanager_proc > create_worker called");
+    /// A manager whose workers are run by `pool` rather than by tasks of
+    /// their own.
+    pub fn pooled(pool: Pool) -> Self {
+        ServiceManager {
+            pool: Some(pool),
+            ..ServiceManager::new()
+        }
+    }
+
+    /// Saves the service to `store` under `srv` right away and then after
+    /// every update or action it commits. Attached once the service has
+    /// been loaded, so that a partly loaded one is never saved.
+    pub async fn persist(&mut self, store: Store, srv: &str) {
+        self.store = Some((store, srv.to_string()));
+        self.save().await;
+    }
+
+    /// Makes the vars and defs declared next start from the code and value
+    /// versions they were saved with, rather than from scratch.
+    pub fn seed_versions(&mut self, versions: HashMap<String, Version>) {
+        self.seeds = versions;
+    }
+
+    /// Names of every declared var and def, sorted.
+    pub fn names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.decls.keys().cloned().collect();
+        names.sort();
+        names
+    }
+
+    pub fn decl(&self, name: &str) -> Option<&meerast::Decl> {
+        self.decls.get(name)
+    }
+
+    pub fn open_session(&mut self, role: Role) -> (SessionId, mpsc::Receiver<Notification>) {
+        /* Forget sessions whose client has gone away */
+        self.developers.retain(|_, sender| !sender.is_closed());
+        self.users.retain(|_, sender| !sender.is_closed());
+        let id = SessionId(self.next_session_id);
+        self.next_session_id += 1;
         let (sndr, rcvr) = mpsc::channel(BUFFER_SIZE);
-        let mut subscriber_addrs = vec![];
-        for n in subscribers.iter() {
-            subscriber_addrs.push((worker_inboxes.get(n)).expect("Worker not exists").clone());
+        match role {
+            Role::Developer => self.developers.insert(id, sndr),
+            Role::User => self.users.insert(id, sndr),
+        };
+        (id, rcvr)
+    }
+
+    /// Checks that `session` is open, and that it is a developer's if
+    /// `developer_only` is set.
+    pub fn authorize(&self, session: SessionId, developer_only: bool) -> Result<(), String> {
+        if self.developers.contains_key(&session) {
+            Ok(())
+        } else if self.users.contains_key(&session) {
+            if developer_only {
+                Err(format!("{} is not a developer", session))
+            } else {
+                Ok(())
+            }
+        } else {
+            Err(format!("{} is not open", session))
         }
-        let worker = Worker::new(rcvr, sender_to_manager.clone(), subscriber_addrs, name);
-        tokio::spawn(run_worker(worker));
+    }
+
+    pub fn notify(&self, session: SessionId, notification: Notification) {
+        let sender = self
+            .developers
+            .get(&session)
+            .or_else(|| self.users.get(&session));
+        if let Some(sender) = sender {
+            let _ = sender.try_send(notification);
+        }
+    }
+
+    /// Starts a propagation round originating at `roots`. The round affects
+    /// the roots and everything downstream of them.
+    fn new_round(&mut self, roots: &HashSet<String>) -> Round {
+        let mut affected = dependency::descendants(&self.dependgraph, roots);
+        affected.extend(roots.iter().cloned());
+        self.start_round(affected)
+    }
+
+    fn start_round(&mut self, affected: HashSet<String>) -> Round {
+        let id = self.next_round_id;
+        self.next_round_id += 1;
+        let round = Round {
+            id,
+            affected: Arc::new(affected),
+        };
+        self.in_flight.begin(&round);
+        round
+    }
+
+    pub fn metrics(&self) -> MetricsSnapshot {
+        self.metrics.snapshot()
+    }
 
-        worker_inboxes.insert(name.to_string(), sndr);
-        locks.insert(name.to_string(), None);
-        typenv.insert(name.to_string(), None);
-        var_or_def_env.insert(name.to_string(), workertype);
-        dependgraph.insert(name.to_string(), subscribers.clone());
+    /// Resolves once every round started so far has been processed by all
+    /// the workers it affects, i.e. once values have settled.
+    pub async fn await_quiescent(&self) {
+        self.in_flight.quiescent().await;
     }
 
-    #[tracing::instrument]
-    pub async fn init_var_worker(
-        worker_inboxes: &mut HashMap<String, mpsc::Sender<Message>>,
+    pub async fn declare(&mut self, decl: meerast::Decl) -> Result<(), String> {
+        match decl {
+            meerast::Decl::Import { srv_name: _ } => {
+                Err(String::from("not yet support multi service"))
+            }
+            meerast::Decl::VarDecl { name, val } => self.declare_var(&name, val).await,
+            meerast::Decl::DefDecl {
+                name,
+                val,
+                is_pub,
+                is_lazy,
+            } => self.declare_def(&name, val, is_pub, is_lazy).await,
+        }
+    }
+
+    /// Declares a new var initialised to `init`, which may not refer to
+    /// other names.
+    #[tracing::instrument(skip(self))]
+    pub async fn declare_var(&mut self, name: &str, init: meerast::Expr) -> Result<(), String> {
+        let mut refs: HashSet<String> = HashSet::new();
+        dependency::expr_dependency(&mut refs, &init);
+        if !refs.is_empty() {
+            return Err(format!("initial value of var `{}` is not closed", name));
+        }
+        let decl = meerast::Decl::VarDecl {
+            name: name.to_string(),
+            val: init.clone(),
+        };
+        self.check_new_decl(name, &decl)?;
+        self.spawn_worker(name, VarOrDef::Var, decl);
+        self.init_var_worker(name, init).await;
+        Ok(())
+    }
+
+    /// Declares a new def. Everything it refers to must be declared already.
+    /// A lazy def is only computed while something observes it.
+    #[tracing::instrument(skip(self))]
+    pub async fn declare_def(
+        &mut self,
         name: &str,
-        var_init_val: meerast::Expr,
-    ) {
+        expr: meerast::Expr,
+        is_pub: bool,
+        is_lazy: bool,
+    ) -> Result<(), String> {
+        let decl = meerast::Decl::DefDecl {
+            name: name.to_string(),
+            val: expr.clone(),
+            is_pub,
+            is_lazy,
+        };
+        self.check_new_decl(name, &decl)?;
+        let mut preds: HashSet<String> = HashSet::new();
+        dependency::expr_dependency(&mut preds, &expr);
+        self.settle().await;
+        self.spawn_worker(name, VarOrDef::Def, decl);
+        let sender = self.worker_inboxes.get(name).unwrap().clone();
+        for pred in preds.iter() {
+            self.dependgraph
+                .get_mut(pred)
+                .unwrap()
+                .insert(name.to_string());
+            self.add_sender_to_succ(pred, sender.clone(), name).await;
+            /* A lazy successor would leave it dormant anyway */
+            if self.is_lazy(pred) && !is_lazy {
+                self.wake(pred).await;
+            }
+        }
+        self.init_def_worker(name, expr).await;
+        Ok(())
+    }
+
+    /// Waits for every round started so far, before workers are given new
+    /// successors. A worker still behind on an earlier round would start a
+    /// new successor with an older value than the one it reports next, in a
+    /// round the successor is not part of and would wait on forever.
+    async fn settle(&mut self) {
+        self.wait_in_flight(1).await;
+    }
+
+    /// Like `settle`, but only waits for the rounds that affect `names`.
+    async fn settle_names(&mut self, names: &HashSet<String>) {
+        loop {
+            let in_flight = self.in_flight.clone();
+            tokio::select! {
+                biased;
+                (name, reason) = self.next_death() => self.restart_worker(&name, reason),
+                _ = in_flight.settled(names) => return,
+            }
+        }
+    }
+
+    /// Waits until fewer than `limit` worker-round pairs are in flight.
+    /// Workers that die meanwhile are restarted, as their rounds would
+    /// never finish otherwise.
+    async fn wait_in_flight(&mut self, limit: usize) {
+        loop {
+            let in_flight = self.in_flight.clone();
+            tokio::select! {
+                biased;
+                (name, reason) = self.next_death() => self.restart_worker(&name, reason),
+                _ = in_flight.below(limit) => return,
+            }
+        }
+    }
+
+    /// Lets the rounds in flight settle, then closes every worker's inbox
+    /// and waits for the worker tasks to return. Workers run by a pool are
+    /// retired by their shards, which other managers may share.
+    pub async fn shut_down(&mut self) {
+        self.settle().await;
+        self.save().await;
+        if let Some((store, _)) = &self.store {
+            if let Err(reason) = store.flush().await {
+                tracing::error!(reason=%reason, "srvmanager_proc > shut_down > snapshot is not saved");
+            }
+        }
+        /* A worker that returns leaves its senders to its successors in its
+         * checkpoint, so those have to go for the successors to return */
+        self.checkpoints.clear();
+        self.worker_inboxes.clear();
+        self.pool = None;
+        while self.watchers.join_next().await.is_some() {}
+        info!("srvmanager_proc > shut_down > every worker has returned");
+    }
+
+    /// Saves the declarations and the value of every var, if the service
+    /// is persisted. A var without a value is left out, so it is
+    /// initialised from its declaration again on restore.
+    async fn save(&mut self) {
+        let Some((store, srv)) = self.store.clone() else {
+            return;
+        };
+        let mut saved = ServiceSnapshot::default();
+        for (name, decl) in self.decls.iter() {
+            if self.upstream.contains_key(name) {
+                continue;
+            }
+            let code_version = self.code_versions.get(name).cloned().unwrap_or(0);
+            saved
+                .decls
+                .insert(name.clone(), (decl.clone(), code_version));
+        }
+        let vars: Vec<String> = saved
+            .decls
+            .iter()
+            .filter(|(_, (decl, _))| matches!(decl, meerast::Decl::VarDecl { .. }))
+            .map(|(name, _)| name.clone())
+            .collect();
+        for name in vars.into_iter() {
+            if let Ok((Some(val), version, _)) = self.apprise(&name).await {
+                saved.vars.insert(name, (val, version));
+            }
+        }
+        store.save(&srv, saved);
+    }
+
+    fn is_lazy(&self, name: &str) -> bool {
+        matches!(
+            self.decls.get(name),
+            Some(meerast::Decl::DefDecl { is_lazy: true, .. })
+        )
+    }
+
+    /* Names the def `name` reads */
+    fn preds(&self, name: &str) -> HashSet<String> {
+        let mut preds: HashSet<String> = HashSet::new();
+        if let Some(meerast::Decl::DefDecl { val, .. }) = self.decls.get(name) {
+            dependency::expr_dependency(&mut preds, val);
+        }
+        preds
+    }
+
+    /// Brings the def `name` back from dormancy, along with the lazy defs it
+    /// reads, which go dormant as well once nothing observes them. Each is
+    /// pinned and has its predecessors send their current value again,
+    /// what it reads first. Resolves once `name` has computed, and keeps it
+    /// pinned until the returned `Pins` are dropped.
+    async fn wake(&mut self, name: &str) -> Pins {
+        let mut graph: HashMap<String, HashSet<String>> = HashMap::new();
+        let mut seen = HashSet::from([name.to_string()]);
+        let mut worklist = vec![name.to_string()];
+        while let Some(next) = worklist.pop() {
+            let preds = self.preds(&next);
+            for pred in preds.iter().filter(|pred| self.is_lazy(pred)) {
+                if seen.insert(pred.clone()) {
+                    worklist.push(pred.clone());
+                }
+            }
+            graph.insert(next, preds);
+        }
+        let pins = Pins(
+            graph
+                .keys()
+                .map(|woken| {
+                    let interest = self.interests.get(woken).unwrap().clone();
+                    interest.pin();
+                    interest
+                })
+                .collect(),
+        );
+        for woken in dependency::topo_order(&graph).into_iter() {
+            let preds = graph.remove(&woken).unwrap();
+            /* Like `add_sender_to_succ` asks for, but only for the rounds
+             * of these predecessors, which include their own wake */
+            self.settle_names(&preds).await;
+            let sender = self.worker_inboxes.get(&woken).unwrap().clone();
+            for pred in preds.iter() {
+                self.add_sender_to_succ(pred, sender.clone(), &woken).await;
+            }
+        }
+        self.settle_names(&HashSet::from([name.to_string()])).await;
+        pins
+    }
+
+    fn check_new_decl(&mut self, name: &str, decl: &meerast::Decl) -> Result<(), String> {
+        if self.decls.contains_key(name) {
+            return Err(format!("`{}` is already declared", name));
+        }
+        let mut decls = self.decls.clone();
+        decls.insert(name.to_string(), decl.clone());
+        let types = ServiceManager::typecheck(&decls, None)?;
+        for (ident, ty) in types.into_iter() {
+            self.typenv.insert(ident, Some(ty));
+        }
+        Ok(())
+    }
+
+    #[tracing::instrument(skip(self))]
+    fn spawn_worker(&mut self, name: &str, workertype: VarOrDef, decl: meerast::Decl) {
+        tracing::info!("srvmanager_proc > spawn_worker called");
+        let interest = Arc::new(Interest::default());
+        let core = |inbox: Inbox| {
+            let mut core = WorkerCore::new(
+                inbox,
+                self.sender_to_manager.clone(),
+                self.in_flight.clone(),
+                self.metrics.clone(),
+                name,
+            );
+            core.interest = interest.clone();
+            core
+        };
+        let addr = match &self.pool {
+            None => {
+                let (sndr, rcvr) = mpsc::unbounded_channel();
+                let core = core(rcvr.into());
+                match workertype {
+                    VarOrDef::Var => self.spawn_task(VarWorker::new(core)),
+                    VarOrDef::Def => self.spawn_task(DefWorker::new(core)),
+                };
+                Addr::Task(sndr)
+            }
+            Some(pool) => {
+                let core = core(Inbox::Queue(VecDeque::new()));
+                self.checkpoints
+                    .insert(name.to_string(), core.checkpoint.clone());
+                let node = match workertype {
+                    VarOrDef::Var => Node::Var(Box::new(VarWorker::new(core))),
+                    VarOrDef::Def => Node::Def(Box::new(DefWorker::new(core))),
+                };
+                pool.spawn(node, self.deaths.0.clone())
+            }
+        };
+
+        self.worker_inboxes.insert(name.to_string(), addr);
+        self.interests.insert(name.to_string(), interest);
+        self.typenv.entry(name.to_string()).or_insert(None);
+        self.var_or_def_env.insert(name.to_string(), workertype);
+        self.dependgraph.insert(name.to_string(), HashSet::new());
+        self.decls.insert(name.to_string(), decl);
+    }
+
+    /* Runs `worker` as a task of its own, watched for panics */
+    fn spawn_task(&mut self, mut worker: impl Worker) {
+        let name = worker.core().name.clone();
+        let checkpoint = worker.core().checkpoint.clone();
+        self.checkpoints.insert(name.clone(), checkpoint.clone());
+        let task = tokio::spawn(worker::run_worker(worker));
+        self.watchers.spawn(watch_worker(name, checkpoint, task));
+    }
+
+    /// Waits for a worker to panic and returns the worker's name and the
+    /// panic message.
+    async fn next_death(&mut self) -> (String, String) {
+        loop {
+            let death = tokio::select! {
+                Some(joined) = self.watchers.join_next() => match joined {
+                    Ok(death) => death,
+                    Err(_) => continue, /* The manager is going away */
+                },
+                Some(death) = self.deaths.1.recv() => death,
+            };
+            let (name, checkpoint, reason) = match death {
+                Death {
+                    name,
+                    checkpoint,
+                    reason: Some(reason),
+                } => (name, checkpoint, reason),
+                _ => continue, /* Removed */
+            };
+            /* Not a worker deleted since, whose name may have been reused */
+            let current = self.checkpoints.get(&name);
+            if current.is_some_and(|current| Arc::ptr_eq(current, &checkpoint)) {
+                return (name, reason);
+            }
+        }
+    }
+
+    /// Replaces the worker of `name`, which panicked, and tells the
+    /// developers. The new worker is set up from the declaration and takes
+    /// over the remains of the dead one: its inbox, so that its
+    /// predecessors and the manager reach it at the same address, its
+    /// subscribers, its value and the rounds it had not finished. The
+    /// round it died computing is reported with its old value.
+    fn restart_worker(&mut self, name: &str, reason: String) {
+        tracing::error!(worker=%name, reason=%reason, "srvmanager_proc > restart_worker > worker died");
+        let revive = self.revive(name);
+        match self.worker_inboxes.get(name) {
+            /* The shard keeps what is sent to the node until it is revived */
+            Some(Addr::Pooled(slot)) => slot.revive(revive),
+            _ => match revive(self.checkpoints.get(name).unwrap().take()) {
+                Node::Var(worker) => self.spawn_task(*worker),
+                Node::Def(worker) => self.spawn_task(*worker),
+            },
+        }
+        for developer in self.developers.values() {
+            let _ = developer.try_send(Notification::WorkerRestarted {
+                name: name.to_string(),
+                reason: reason.clone(),
+            });
+        }
+    }
+
+    /* Builds the worker of `name` anew from what the dead one left */
+    fn revive(&self, name: &str) -> Revive {
+        let checkpoint = self.checkpoints.get(name).unwrap().clone();
+        let interest = self.interests.get(name).unwrap().clone();
+        let sender_to_manager = self.sender_to_manager.clone();
+        let in_flight = self.in_flight.clone();
+        let metrics = self.metrics.clone();
+        let code_version = self.code_versions.get(name).cloned().unwrap_or(0);
+        let decl = self.decls.get(name).cloned();
+        let name = name.to_string();
+        Box::new(move |mut remains| {
+            let mut core = WorkerCore::new(
+                remains.inbox.take().unwrap(),
+                sender_to_manager,
+                in_flight,
+                metrics,
+                &name,
+            );
+            core.checkpoint = checkpoint;
+            core.interest = interest;
+            core.version.code = code_version;
+            match decl {
+                Some(meerast::Decl::DefDecl { val, is_lazy, .. }) => Node::Def(Box::new(
+                    DefWorker::restore(core, val, is_lazy, &mut remains),
+                )),
+                _ => Node::Var(Box::new(VarWorker::restore(core, &mut remains))),
+            }
+        })
+    }
+
+    async fn init_var_worker(&mut self, name: &str, var_init_val: meerast::Expr) {
         info!(
             name=%name,
             var_init_val=?var_init_val,
-            worker_inboxes=?worker_inboxes,
             "srvmanager_proc > init_var_worker called"
         );
-        let worker_addr = worker_inboxes.get(name).unwrap();
+        let (code_version, value_version) = self.init_versions(name);
+        let round = self.new_round(&HashSet::from([name.to_string()]));
+        let worker_addr = self.worker_inboxes.get(name).unwrap();
         let msg = Message::InitVar {
             var_name: name.to_string(),
             var_expr: var_init_val,
+            code_version,
+            value_version,
+            round,
         };
         info!(send_message=?msg, "srvmanager_proc > init_def_worker > send InitVar message");
-        let _ = worker_addr.send(msg).await.expect("Init val fails");
+        worker_addr.send(msg).expect("Init val fails");
     }
 
-    #[tracing::instrument]
-    pub async fn init_def_worker(
-        worker_inboxes: &HashMap<String, mpsc::Sender<Message>>,
-        name: &str,
-        def_init_expr: meerast::Expr,
-    ) {
+    async fn init_def_worker(&mut self, name: &str, def_init_expr: meerast::Expr) {
         info!(
             name=%name,
             var_init_val=?def_init_expr,
-            worker_inboxes=?worker_inboxes,
             "srvmanager_proc > init_def_worker called"
         );
-        let worker_addr = worker_inboxes.get(name).unwrap();
+        let (code_version, _) = self.init_versions(name);
+        let round = self.new_round(&HashSet::from([name.to_string()]));
+        let worker_addr = self.worker_inboxes.get(name).unwrap();
         let msg = Message::InitDef {
             def_name: name.to_string(),
             def_expr: def_init_expr,
+            code_version,
+            lazy: self.is_lazy(name),
+            round,
         };
         info!(send_message=?msg, "srvmanager_proc > init_def_worker > send InitDef message");
-        let _ = worker_addr.send(msg).await.expect("Init def fails");
+        worker_addr.send(msg).expect("Init def fails");
+    }
+
+    /* The code version a worker is (re)initialised with, and the value
+     * version it starts from if it is restored from a snapshot */
+    fn init_versions(&mut self, name: &str) -> (u64, Option<u64>) {
+        match self.seeds.remove(name) {
+            Some(seed) => {
+                self.code_versions.insert(name.to_string(), seed.code);
+                (seed.code, Some(seed.value))
+            }
+            None => (self.bump_code_version(name), None),
+        }
+    }
+
+    fn bump_code_version(&mut self, name: &str) -> u64 {
+        let code_version = self.code_versions.entry(name.to_string()).or_insert(0);
+        *code_version += 1;
+        *code_version
+    }
+
+    /// Writes all of `writes` in a single round, so defs downstream of
+    /// several of the vars see the writes together.
+    #[tracing::instrument(skip(self))]
+    async fn write_vars(&mut self, writes: HashMap<String, Val>) {
+        if writes.is_empty() {
+            return;
+        }
+        /* Held back while workers are behind, so that their inboxes, which
+         * are unbounded, stay bounded in practice */
+        self.wait_in_flight(IN_FLIGHT_LIMIT).await;
+        let round = self.new_round(&writes.keys().cloned().collect());
+        for (name, new_val) in writes.into_iter() {
+            let worker_addr = self.worker_inboxes.get(&name).unwrap();
+            let msg = Message::WriteVar {
+                new_val,
+                round: round.clone(),
+            };
+            info!(send_message=?msg, "srvmanager_proc > write_vars > send WriteVar message");
+            worker_addr.send(msg).expect("Write var fails");
+        }
+    }
+
+    /// Runs a user action: its reads see the values from before the action
+    /// and all of its writes are propagated in one round.
+    #[tracing::instrument(skip(self))]
+    pub async fn run_action(&mut self, action: meerast::Expr) -> Result<(), String> {
+        ServiceManager::typecheck(&self.decls, Some(&action))?;
+        let rw_set = self.rw_set(&action)?;
+        info!(rw_set=?rw_set, "srvmanager_proc > run_action > read/write set");
+
+        let mut replica: HashMap<String, Val> = HashMap::new();
+        for name in self.referenced_names(&action).into_iter() {
+            match self.apprise(&name).await? {
+                (Some(val), _, _) => {
+                    replica.insert(name, val);
+                }
+                (None, _, pending) => {
+                    return Err(format!(
+                        "`{}` has no value yet: {}",
+                        name,
+                        pending.unwrap_or_default()
+                    ))
+                }
+            }
+        }
+        let mut writes: HashMap<String, Val> = HashMap::new();
+        ServiceManager::eval_action(&action, &replica, &mut writes)?;
+        self.write_vars(writes).await;
+        Ok(())
+    }
+
+    fn rw_set(&self, action: &meerast::Expr) -> Result<rwset::RwSet, String> {
+        let mut vars: HashSet<String> = HashSet::new();
+        let mut defs: HashMap<String, meerast::Expr> = HashMap::new();
+        for (name, decl) in self.decls.iter() {
+            match decl {
+                meerast::Decl::VarDecl { .. } => {
+                    vars.insert(name.clone());
+                }
+                meerast::Decl::DefDecl { val, .. } => {
+                    defs.insert(name.clone(), val.clone());
+                }
+                meerast::Decl::Import { srv_name: _ } => {}
+            }
+        }
+        rwset::action_rw_set(action, &vars, &defs)
+    }
+
+    fn eval_action(
+        action: &meerast::Expr,
+        replica: &HashMap<String, Val>,
+        writes: &mut HashMap<String, Val>,
+    ) -> Result<(), String> {
+        let sgls = match worker::compute_val(action, replica) {
+            Val::Action(meerast::Expr::Action {
+                stmt: meerast::Stmt::Stmt { sgl_stmts },
+            }) => sgl_stmts,
+            val => return Err(format!("`do` on non action value {:?}", val)),
+        };
+        for sgl in sgls.iter() {
+            match sgl {
+                meerast::SglStmt::Do { act } => ServiceManager::eval_action(act, replica, writes)?,
+                meerast::SglStmt::Ass { dst, src } => {
+                    let dst_name = match dst {
+                        meerast::Expr::IdExpr { ident } => ident.clone(),
+                        _ => return Err(format!("assignment to non identifier {:?}", dst)),
+                    };
+                    writes.insert(dst_name, worker::compute_val(src, replica));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Makes `succ`, whose inbox is `sender`, receive every `PredUpdatedTo`
+    /// that `name` emits from now on, starting with its current value.
+    /// `name` must have applied every earlier round already, see
+    /// `settle`.
+    async fn add_sender_to_succ(&mut self, name: &str, sender: Addr, succ: &str) {
+        let interest = self.interests.get(succ).unwrap().clone();
+        /* Only `succ` and what lies downstream of it hear from `name` in
+         * this round, `name`'s other successors must not wait for it. */
+        let succ = HashSet::from([succ.to_string()]);
+        let mut affected = HashSet::from([name.to_string()]);
+        affected.extend(dependency::descendants(&self.dependgraph, &succ));
+        affected.extend(succ);
+        let round = self.start_round(affected);
+        let worker_addr = self.worker_inboxes.get(name).unwrap();
+        worker_addr
+            .send(Message::AddSenderToSucc {
+                sender,
+                interest,
+                round,
+            })
+            .expect("Add successor fails");
+    }
+
+    /// Replaces the declaration of an existing var or def. The new service
+    /// must typecheck as a whole, otherwise the update is rejected and
+    /// nothing changes.
+    #[tracing::instrument(skip(self))]
+    pub async fn update(&mut self, decl: meerast::Decl) -> Result<(), String> {
+        let (name, new_val) = match &decl {
+            meerast::Decl::Import { srv_name: _ } => {
+                return Err(String::from("not yet support multi service"))
+            }
+            meerast::Decl::VarDecl { name, val } => match self.var_or_def_env.get(name) {
+                Some(VarOrDef::Var) => {
+                    let mut refs: HashSet<String> = HashSet::new();
+                    dependency::expr_dependency(&mut refs, val);
+                    if !refs.is_empty() {
+                        return Err(format!("initial value of var `{}` is not closed", name));
+                    }
+                    (name.clone(), val.clone())
+                }
+                Some(VarOrDef::Def) => return Err(format!("`{}` is a def, not a var", name)),
+                None => return Err(format!("`{}` is not declared", name)),
+            },
+            meerast::Decl::DefDecl { name, val, .. } => match self.var_or_def_env.get(name) {
+                Some(VarOrDef::Def) => (name.clone(), val.clone()),
+                Some(VarOrDef::Var) => return Err(format!("`{}` is a var, not a def", name)),
+                None => return Err(format!("`{}` is not declared", name)),
+            },
+        };
+        let mut decls = self.decls.clone();
+        let old_decl = decls.insert(name.clone(), decl.clone());
+        let types = ServiceManager::typecheck(&decls, None)?;
+
+        let mut old_preds: HashSet<String> = HashSet::new();
+        if let Some(meerast::Decl::DefDecl { val, .. }) = &old_decl {
+            dependency::expr_dependency(&mut old_preds, val);
+        }
+        let mut new_preds: HashSet<String> = HashSet::new();
+        if let meerast::Decl::DefDecl { val, .. } = &decl {
+            dependency::expr_dependency(&mut new_preds, val);
+        }
+        let mut dependgraph = self.dependgraph.clone();
+        for pred in old_preds.difference(&new_preds) {
+            if let Some(succs) = dependgraph.get_mut(pred) {
+                succs.remove(&name);
+            }
+        }
+        for pred in new_preds.difference(&old_preds) {
+            match dependgraph.get_mut(pred) {
+                Some(succs) => succs.insert(name.clone()),
+                None => return Err(format!("`{}` is not declared", pred)),
+            };
+        }
+        let name_set = HashSet::from([name.clone()]);
+        if dependency::descendants(&dependgraph, &name_set).contains(&name) {
+            return Err(format!("updating `{}` introduces cyclic dependency", name));
+        }
+
+        self.decls = decls;
+        self.dependgraph = dependgraph;
+        for (ident, ty) in types.into_iter() {
+            self.typenv.insert(ident, Some(ty));
+        }
+        self.settle().await;
+        let sender = self.worker_inboxes.get(&name).unwrap().clone();
+        for pred in old_preds.difference(&new_preds) {
+            if let Some(pred_addr) = self.worker_inboxes.get(pred) {
+                let _ = pred_addr.send(Message::RemoveSenderToSucc {
+                    sender: sender.clone(),
+                });
+            }
+        }
+        for pred in new_preds.difference(&old_preds) {
+            self.add_sender_to_succ(pred, sender.clone(), &name).await;
+            if self.is_lazy(pred) && !self.is_lazy(&name) {
+                self.wake(pred).await;
+            }
+        }
+        match decl {
+            meerast::Decl::VarDecl { .. } => self.init_var_worker(&name, new_val).await,
+            _ => self.init_def_worker(&name, new_val).await,
+        }
+        /* A def that has stopped being lazy may have been dormant */
+        if matches!(old_decl, Some(meerast::Decl::DefDecl { is_lazy: true, .. })) {
+            self.wake(&name).await;
+        }
+        Ok(())
+    }
+
+    /// Queues a user action, remembering the code version of every name it
+    /// refers to, so it can be checked for staleness when it is finally run.
+    pub fn queue_action(&self, action: meerast::Expr) -> QueuedAction {
+        let captured_versions = self
+            .referenced_names(&action)
+            .into_iter()
+            .map(|name| {
+                let code_version = self.code_versions.get(&name).cloned().unwrap_or(0);
+                (name, code_version)
+            })
+            .collect();
+        QueuedAction {
+            action,
+            captured_versions,
+        }
+    }
+
+    /// If an `update` has changed the code of anything a queued action
+    /// refers to since it was queued, the action is typechecked again
+    /// against the current service and rejected if that fails.
+    pub fn dequeue_action(&self, queued: QueuedAction) -> Result<meerast::Expr, String> {
+        let stale = queued.captured_versions.iter().any(|(name, code_version)| {
+            self.code_versions.get(name).cloned().unwrap_or(0) != *code_version
+        });
+        if !stale {
+            return Ok(queued.action);
+        }
+        info!(action=?queued.action, "srvmanager_proc > dequeue_action > re-typecheck stale action");
+        ServiceManager::typecheck(&self.decls, Some(&queued.action))
+            .map(|_| queued.action)
+            .map_err(|err_msg| format!("action rejected after code update: {}", err_msg))
+    }
+
+    /* Names `expr` refers to, directly or through the defs it reads */
+    fn referenced_names(&self, expr: &meerast::Expr) -> HashSet<String> {
+        let mut rslt: HashSet<String> = HashSet::new();
+        dependency::expr_dependency(&mut rslt, expr);
+        let mut worklist: Vec<String> = rslt.iter().cloned().collect();
+        while let Some(name) = worklist.pop() {
+            if let Some(meerast::Decl::DefDecl { val, .. }) = self.decls.get(&name) {
+                let mut deps: HashSet<String> = HashSet::new();
+                dependency::expr_dependency(&mut deps, val);
+                for dep in deps.into_iter() {
+                    if rslt.insert(dep.clone()) {
+                        worklist.push(dep);
+                    }
+                }
+            }
+        }
+        rslt
+    }
+
+    fn typecheck(
+        decls: &HashMap<String, meerast::Decl>,
+        action: Option<&meerast::Expr>,
+    ) -> Result<HashMap<String, typecheck::Type>, String> {
+        let mut dependency_graph: HashMap<String, HashSet<String>> = HashMap::new();
+        for (name, decl) in decls.iter() {
+            dependency_graph.insert(name.clone(), HashSet::new());
+            dependency::decl_dependency(&mut dependency_graph, decl);
+        }
+        let mut used: HashSet<String> = HashSet::new();
+        for deps in dependency_graph.values() {
+            used.extend(deps.iter().cloned());
+        }
+        if let Some(action) = action {
+            dependency::expr_dependency(&mut used, action);
+        }
+        if let Some(undeclared) = used.iter().find(|name| !decls.contains_key(*name)) {
+            return Err(format!("`{}` is not declared", undeclared));
+        }
+        let ordered_decls: Vec<&meerast::Decl> = dependency::topo_order(&dependency_graph)
+            .iter()
+            .map(|name| decls.get(name).unwrap())
+            .collect();
+        typecheck::check_decls_then_action("service", &ordered_decls, action)
+    }
+
+    /// Removes a var or def and shuts its worker down. Refuses if other
+    /// nodes still depend on `name`, unless `cascade` is set, in which case
+    /// everything downstream of `name` is deleted as well.
+    #[tracing::instrument(skip(self))]
+    pub async fn delete(&mut self, name: &str, cascade: bool) -> Result<(), String> {
+        let succs = match self.dependgraph.get(name) {
+            Some(succs) => succs.clone(),
+            None => return Err(format!("`{}` is not declared", name)),
+        };
+        if !succs.is_empty() && !cascade {
+            let mut dependents: Vec<String> = succs.into_iter().collect();
+            dependents.sort();
+            return Err(format!(
+                "`{}` is still used by {}",
+                name,
+                dependents.join(", ")
+            ));
+        }
+        /* A predecessor still behind on a round would drop a deleted worker
+         * before reporting that round to it, which would then never finish */
+        self.settle().await;
+        let name_set = HashSet::from([name.to_string()]);
+        let mut doomed = dependency::descendants(&self.dependgraph, &name_set);
+        doomed.insert(name.to_string());
+        /* Delete dependents before what they depend on */
+        let mut doomed_graph: HashMap<String, HashSet<String>> = HashMap::new();
+        for n in doomed.iter() {
+            doomed_graph.insert(
+                n.clone(),
+                self.dependgraph.get(n).cloned().unwrap_or_default(),
+            );
+        }
+        for n in dependency::topo_order(&doomed_graph).into_iter() {
+            self.remove_worker(&n).await;
+        }
+        Ok(())
+    }
+
+    async fn remove_worker(&mut self, name: &str) {
+        info!(name=%name, "srvmanager_proc > remove_worker");
+        let inbox = match self.worker_inboxes.remove(name) {
+            Some(inbox) => inbox,
+            None => return,
+        };
+        let preds: Vec<String> = self
+            .dependgraph
+            .iter()
+            .filter(|(_, succs)| succs.contains(name))
+            .map(|(pred, _)| pred.clone())
+            .collect();
+        for pred in preds.iter() {
+            self.dependgraph.get_mut(pred).unwrap().remove(name);
+            if let Some(pred_addr) = self.worker_inboxes.get(pred) {
+                let _ = pred_addr.send(Message::RemoveSenderToSucc {
+                    sender: inbox.clone(),
+                });
+            }
+        }
+        /* Once the predecessors have dropped their copies as well, the
+         * worker's inbox closes and its task returns, or its pool retires
+         * it. */
+        drop(inbox);
+        self.upstream.remove(name);
+        self.downstream.remove(name);
+        self.typenv.remove(name);
+        self.var_or_def_env.remove(name);
+        self.dependgraph.remove(name);
+        self.decls.remove(name);
+        self.code_versions.remove(name);
+        self.checkpoints.remove(name);
+        self.interests.remove(name);
+    }
+
+    /// Subscribes to the public def `name`. The subscription yields the
+    /// current value first and then every new version. A dormant lazy def
+    /// is woken and stays live until its last subscription is dropped.
+    pub async fn subscribe(&mut self, name: &str) -> Result<Subscription, String> {
+        match self.decls.get(name) {
+            Some(meerast::Decl::DefDecl { is_pub: true, .. }) => {}
+            Some(_) => return Err(format!("`{}` is not a public def", name)),
+            None => return Err(format!("`{}` is not declared", name)),
+        }
+        let (sender, receiver) = mpsc::channel(BUFFER_SIZE);
+        self.worker_inboxes
+            .get(name)
+            .unwrap()
+            .send(Message::Subscribe { sender })
+            .map_err(|_| format!("worker `{}` is gone", name))?;
+        if self.is_lazy(name) {
+            self.apprise(name).await?;
+        }
+        Ok(Subscription::new(receiver))
     }
 
-    pub async fn retrieve_val(
-        worker_inboxes: &HashMap<String, mpsc::Sender<Message>>,
-        receiver_from_workers: &mut mpsc::Receiver<Message>,
+    /// Makes every transaction that writes `name` also lock the subscriber's
+    /// mirror of it, through `locker`.
+    pub fn add_locker(&mut self, name: &str, locker: mpsc::Sender<LockCall>) {
+        let lockers = self.downstream.entry(name.to_string()).or_default();
+        lockers.retain(|locker| !locker.is_closed());
+        lockers.push(locker);
+    }
+
+    /// Records that the var `mirror` mirrors `member` of the service `peer`,
+    /// so that transactions reading `mirror` lock `member` there.
+    pub fn register_mirror(&mut self, mirror: &str, peer: Peer, member: &str) {
+        self.upstream
+            .insert(mirror.to_string(), (peer, member.to_string()));
+    }
+
+    /// The locks a transaction needs: a write lock on every name it writes
+    /// and everything downstream of those, and a read lock on what it reads.
+    /// Reading a mirror also read locks the member at its owner, and writing
+    /// a public def write locks the mirrors of its subscribers.
+    fn lock_plan(
+        &self,
+        reads: HashSet<String>,
+        writes: HashSet<String>,
+    ) -> (Vec<(String, LockType)>, Vec<LockTarget>) {
+        let mut writes: HashSet<String> = writes
+            .into_iter()
+            .filter(|name| self.decls.contains_key(name))
+            .collect();
+        writes.extend(dependency::descendants(&self.dependgraph, &writes));
+        let mut local: Vec<(String, LockType)> = writes
+            .iter()
+            .map(|name| (name.clone(), LockType::WLock))
+            .chain(
+                reads
+                    .into_iter()
+                    .filter(|name| self.decls.contains_key(name) && !writes.contains(name))
+                    .map(|name| (name, LockType::RLock)),
+            )
+            .collect();
+        /* A fixed order, so two transactions meet on their first conflict */
+        local.sort();
+        let mut elsewhere: Vec<LockTarget> = vec![];
+        for (name, kind) in local.iter() {
+            match (kind, self.upstream.get(name)) {
+                (LockType::RLock, Some((peer, member))) => elsewhere.push(LockTarget::Owner {
+                    peer: peer.clone(),
+                    name: member.clone(),
+                    kind: LockType::RLock,
+                }),
+                (LockType::WLock, _) => {
+                    for locker in self.downstream.get(name).into_iter().flatten() {
+                        elsewhere.push(LockTarget::Downstream {
+                            locker: locker.clone(),
+                            kind: LockType::WLock,
+                        });
+                    }
+                }
+                _ => {}
+            }
+        }
+        (local, elsewhere)
+    }
+
+    fn update_lock_plan(&self, update: &CodeUpdate) -> (Vec<(String, LockType)>, Vec<LockTarget>) {
+        let mut reads: HashSet<String> = HashSet::new();
+        if let CodeUpdate::Declare(decl) | CodeUpdate::Update(decl) = update {
+            if let meerast::Decl::VarDecl { val, .. } | meerast::Decl::DefDecl { val, .. } = decl {
+                dependency::expr_dependency(&mut reads, val);
+            }
+        }
+        self.lock_plan(reads, HashSet::from([update.name()]))
+    }
+
+    fn action_lock_plan(
+        &self,
+        action: &meerast::Expr,
+    ) -> (Vec<(String, LockType)>, Vec<LockTarget>) {
+        /* An action whose read/write set cannot be computed fails to run */
+        let rw_set = self.rw_set(action).unwrap_or_default();
+        self.lock_plan(rw_set.reads, rw_set.writes)
+    }
+
+    /// Asks `name` for its current value. Each request gets its own reply
+    /// channel, so any number of reads may be in flight at once.
+    pub async fn read(&mut self, name: &str) -> Result<(Option<Val>, Version), String> {
+        let (val, version, _) = self.apprise(name).await?;
+        Ok((val, version))
+    }
+
+    /// Like `read`, along with the reason `name` is still pending if it has
+    /// no value. A dormant lazy def is woken first, and kept from going
+    /// dormant again until it has answered.
+    pub async fn apprise(
+        &mut self,
         name: &str,
-    ) -> Option<Val> {
-        let worker_addr = worker_inboxes.get(name).unwrap();
-        let msg = Message::RetrieveVal;
-        let _ = worker_addr
-            .send(msg)
+    ) -> Result<(Option<Val>, Version, Option<String>), String> {
+        let worker_addr = match self.worker_inboxes.get(name) {
+            Some(addr) => addr.clone(),
+            None => return Err(format!("`{}` is not declared", name)),
+        };
+        let apprised = retrieve(&worker_addr, name).await?;
+        if !apprised.dormant {
+            return Ok((apprised.value, apprised.version, apprised.pending));
+        }
+        let _pins = self.wake(name).await;
+        let apprised = retrieve(&worker_addr, name).await?;
+        Ok((apprised.value, apprised.version, apprised.pending))
+    }
+}
+
+/// Keeps the defs a wake has pinned observing until dropped.
+struct Pins(Vec<Arc<Interest>>);
+
+impl Drop for Pins {
+    fn drop(&mut self) {
+        for interest in self.0.iter() {
+            interest.unpin();
+        }
+    }
+}
+
+/* What a worker answers to `RetrieveVal` */
+struct Apprised {
+    value: Option<Val>,
+    version: Version,
+    pending: Option<String>,
+    dormant: bool,
+}
+
+async fn retrieve(worker_addr: &Addr, name: &str) -> Result<Apprised, String> {
+    let (reply_to, reply) = oneshot::channel();
+    worker_addr
+        .send(Message::RetrieveVal { reply_to })
+        .map_err(|_| format!("worker `{}` is gone", name))?;
+    match reply.await {
+        Ok(Message::AppriseVal {
+            worker_name: _,
+            worker_value,
+            worker_version,
+            pending,
+            dormant,
+        }) => Ok(Apprised {
+            value: worker_value,
+            version: worker_version,
+            pending,
+            dormant,
+        }),
+        Ok(msg) => Err(format!("unexpected reply {:?} from `{}`", msg, name)),
+        Err(_) => Err(format!("worker `{}` dropped the request", name)),
+    }
+}
+
+/// A client's handle on a service manager running as its own task. Handles
+/// are cheap to clone; the manager stops once every handle is dropped and
+/// its queues are drained.
+#[derive(Debug, Clone)]
+pub struct ServiceHandle {
+    commands: mpsc::Sender<Command>,
+}
+
+impl ServiceHandle {
+    pub(crate) async fn request<T>(
+        &self,
+        cmd: impl FnOnce(Reply<T>) -> Command,
+    ) -> Result<T, String> {
+        let (reply_to, reply) = oneshot::channel();
+        self.commands
+            .send(cmd(reply_to))
             .await
-            .expect("No val retreived from actor");
-        match receiver_from_workers
-            .recv()
+            .map_err(|_| String::from("service manager is gone"))?;
+        reply
             .await
-            .expect("No val retrieved from worker")
+            .map_err(|_| String::from("service manager dropped the request"))?
+    }
+
+    /// Opens a developer or user session, through which code updates and
+    /// actions are submitted.
+    pub async fn open_session(&self, role: Role) -> Result<Session, String> {
+        let (id, notifications) = self
+            .request(|reply_to| Command::OpenSession { role, reply_to })
+            .await?;
+        Ok(Session::new(id, role, self.clone(), notifications))
+    }
+
+    pub async fn read(&self, name: &str) -> Result<(Option<Val>, Version), String> {
+        self.request(|reply_to| Command::Read {
+            name: name.to_string(),
+            reply_to,
+        })
+        .await
+    }
+
+    /// Subscribes to every new `(value, version)` of the public def `name`.
+    pub async fn subscribe(&self, name: &str) -> Result<Subscription, String> {
+        self.request(|reply_to| Command::Subscribe {
+            name: name.to_string(),
+            locker: None,
+            reply_to,
+        })
+        .await
+    }
+
+    /// Like `subscribe`, for a subscriber that mirrors `name`: transactions
+    /// writing `name` lock the mirror through `locker` first.
+    pub async fn subscribe_mirror(
+        &self,
+        name: &str,
+        locker: mpsc::Sender<LockCall>,
+    ) -> Result<Subscription, String> {
+        self.request(|reply_to| Command::Subscribe {
+            name: name.to_string(),
+            locker: Some(locker),
+            reply_to,
+        })
+        .await
+    }
+
+    pub async fn register_mirror(
+        &self,
+        mirror: &str,
+        peer: Peer,
+        member: &str,
+    ) -> Result<(), String> {
+        self.request(|reply_to| Command::RegisterMirror {
+            mirror: mirror.to_string(),
+            peer,
+            member: member.to_string(),
+            reply_to,
+        })
+        .await
+    }
+
+    /// Locks `name` for `txn`. Fails if `txn` has to die under wait-die.
+    pub async fn lock(&self, txn: TxnId, name: &str, kind: LockType) -> Result<(), String> {
+        self.request(|reply_to| Command::Lock {
+            txn,
+            name: name.to_string(),
+            kind,
+            reply_to,
+        })
+        .await
+    }
+
+    /// Releases every lock `txn` holds or waits for here.
+    pub async fn release(&self, txn: TxnId) {
+        let _ = self.commands.send(Command::Release { txn }).await;
+    }
+
+    /// How much recomputation the defs of the service have done and saved.
+    pub async fn metrics(&self) -> Result<MetricsSnapshot, String> {
+        let (reply_to, reply) = oneshot::channel();
+        self.commands
+            .send(Command::Metrics { reply_to })
+            .await
+            .map_err(|_| String::from("service manager is gone"))?;
+        reply
+            .await
+            .map_err(|_| String::from("service manager dropped the request"))
+    }
+
+    /// Resolves once every update and action submitted so far has run and
+    /// its effects have settled.
+    pub async fn await_quiescent(&self) {
+        let (reply_to, reply) = oneshot::channel();
+        if self
+            .commands
+            .send(Command::AwaitQuiescent { reply_to })
+            .await
+            .is_ok()
+        {
+            let _ = reply.await;
+        }
+    }
+
+    /// See `ServiceManager::seed_versions`.
+    pub async fn seed_versions(&self, versions: HashMap<String, Version>) -> Result<(), String> {
+        self.request(|reply_to| Command::SeedVersions { versions, reply_to })
+            .await
+    }
+
+    /// See `ServiceManager::persist`.
+    pub async fn persist(&self, store: Store, srv: &str) -> Result<(), String> {
+        self.request(|reply_to| Command::Persist {
+            store,
+            srv: srv.to_string(),
+            reply_to,
+        })
+        .await
+    }
+
+    /// Stops the service. Updates and actions submitted from now on are
+    /// refused and those still queued are aborted, while the one running
+    /// finishes. Resolves once its effects have settled and the workers
+    /// have returned.
+    pub async fn shutdown(&self) {
+        let (reply_to, reply) = oneshot::channel();
+        if self
+            .commands
+            .send(Command::Shutdown { reply_to })
+            .await
+            .is_ok()
         {
-            Message::AppriseVal {
-                worker_name,
-                worker_value,
-            } => worker_value,
-            _ => panic!("unexpected message from worker to service manager"),
+            let _ = reply.await;
         }
     }
 }
 
-// TODO:
-// create a new developer thread
+/// A stream of `(value, version)` updates of a public def. Dropping it
+/// unsubscribes.
+#[derive(Debug)]
+pub struct Subscription {
+    receiver: mpsc::Receiver<(Val, Version)>,
+}
+
+impl Subscription {
+    pub(crate) fn new(receiver: mpsc::Receiver<(Val, Version)>) -> Subscription {
+        Subscription { receiver }
+    }
+
+    pub async fn recv(&mut self) -> Option<(Val, Version)> {
+        self.receiver.recv().await
+    }
+
+    /// The next update if one has arrived already.
+    pub fn try_recv(&mut self) -> Option<(Val, Version)> {
+        self.receiver.try_recv().ok()
+    }
+}
+
+impl ServiceManager {
+    /// Moves the manager onto its own task, which owns the R and E queues
+    /// and serves commands sent through the returned handle.
+    pub fn spawn(mut self) -> ServiceHandle {
+        if let Some(rejections) = self.receiver_from_workers.take() {
+            tokio::spawn(report_rejections(rejections));
+        }
+        let (sndr, rcvr) = mpsc::channel(BUFFER_SIZE);
+        tokio::spawn(run_manager(self, rcvr));
+        ServiceHandle { commands: sndr }
+    }
+}
 
-// syntax abstraction,
-// statically evaluates read/write set
-// type check
+async fn watch_worker(name: String, checkpoint: Arc<Checkpoint>, task: JoinHandle<()>) -> Death {
+    let reason = match task.await {
+        Err(err) if err.is_panic() => Some(worker::panic_reason(err.into_panic())),
+        _ => None,
+    };
+    Death {
+        name,
+        checkpoint,
+        reason,
+    }
+}
+
+async fn report_rejections(mut rejections: mpsc::Receiver<Message>) {
+    while let Some(msg) = rejections.recv().await {
+        if let Message::Rejected {
+            worker_name,
+            reason,
+        } = msg
+        {
+            tracing::warn!(worker=%worker_name, reason=%reason, "srvmanager_proc > worker rejected a message");
+        }
+    }
+}
+
+#[derive(Debug)]
+enum CodeUpdate {
+    Declare(meerast::Decl),
+    Update(meerast::Decl),
+    Delete { name: String, cascade: bool },
+}
+
+impl CodeUpdate {
+    fn name(&self) -> String {
+        match self {
+            CodeUpdate::Declare(decl) | CodeUpdate::Update(decl) => match decl {
+                meerast::Decl::Import { srv_name } => srv_name.clone(),
+                meerast::Decl::VarDecl { name, .. } | meerast::Decl::DefDecl { name, .. } => {
+                    name.clone()
+                }
+            },
+            CodeUpdate::Delete { name, .. } => name.clone(),
+        }
+    }
+}
+
+/// A queued code update or action. A transaction that dies under wait-die
+/// is queued again with its `txn`, so it keeps its age and eventually wins.
+struct Queued<T> {
+    session: SessionId,
+    item: T,
+    reply_to: Reply<()>,
+    txn: Option<TxnId>,
+    restarts: u32,
+}
+
+impl<T> Queued<T> {
+    fn new(session: SessionId, item: T, reply_to: Reply<()>) -> Queued<T> {
+        Queued {
+            session,
+            item,
+            reply_to,
+            txn: None,
+            restarts: 0,
+        }
+    }
+}
+
+/// The random queues R (code updates from developers) and E (actions from
+/// users): the next element is picked uniformly at random, and so is the
+/// queue when both are non-empty.
+struct Queues {
+    code_updates: Vec<Queued<CodeUpdate>>,
+    actions: Vec<Queued<QueuedAction>>,
+    quiescence_waiters: Vec<oneshot::Sender<()>>,
+    shutdown_waiters: Vec<oneshot::Sender<()>>, /* Non-empty once shutting down */
+}
+
+impl Queues {
+    /// Refuses updates and actions once the service is shutting down.
+    fn accepting(&self) -> Result<(), String> {
+        if self.shutdown_waiters.is_empty() {
+            Ok(())
+        } else {
+            Err(String::from("service is shutting down"))
+        }
+    }
+}
+
+/// Restarts after which a transaction that keeps dying is aborted.
+const MAX_RESTARTS: u32 = 32;
+
+async fn run_manager(mut manager: ServiceManager, mut inbox: mpsc::Receiver<Command>) {
+    let mut queues = Queues {
+        code_updates: vec![],
+        actions: vec![],
+        quiescence_waiters: vec![],
+        shutdown_waiters: vec![],
+    };
+    loop {
+        if !queues.shutdown_waiters.is_empty() {
+            abort_queued(&manager, &mut queues);
+            break;
+        }
+        if queues.code_updates.is_empty() && queues.actions.is_empty() {
+            for reply_to in queues.quiescence_waiters.drain(..) {
+                let in_flight = manager.in_flight.clone();
+                tokio::spawn(async move {
+                    in_flight.quiescent().await;
+                    let _ = reply_to.send(());
+                });
+            }
+            tokio::select! {
+                cmd = inbox.recv() => match cmd {
+                    Some(cmd) => accept_command(&mut manager, &mut queues, cmd).await,
+                    None => break,
+                },
+                (name, reason) = manager.next_death() => manager.restart_worker(&name, reason),
+            }
+        } else {
+            while let Ok(cmd) = inbox.try_recv() {
+                accept_command(&mut manager, &mut queues, cmd).await;
+            }
+            step(&mut manager, &mut queues, &mut inbox).await;
+        }
+    }
+    manager.shut_down().await;
+    for reply_to in queues
+        .quiescence_waiters
+        .drain(..)
+        .chain(queues.shutdown_waiters.drain(..))
+    {
+        let _ = reply_to.send(());
+    }
+}
+
+/* Aborts the updates and actions that have not run yet, as the service is
+ * shutting down */
+fn abort_queued(manager: &ServiceManager, queues: &mut Queues) {
+    let reason = String::from("service is shutting down");
+    for queued in queues.code_updates.drain(..) {
+        let name = queued.item.name();
+        let _ = queued.reply_to.send(Err(reason.clone()));
+        let reason = reason.clone();
+        manager.notify(queued.session, Notification::UpdateAborted { name, reason });
+    }
+    for queued in queues.actions.drain(..) {
+        let action = queued.item.action;
+        let _ = queued.reply_to.send(Err(reason.clone()));
+        let reason = reason.clone();
+        manager.notify(
+            queued.session,
+            Notification::ActionAborted { action, reason },
+        );
+    }
+}
+
+#[tracing::instrument(skip(manager, queues))]
+async fn accept_command(manager: &mut ServiceManager, queues: &mut Queues, cmd: Command) {
+    match cmd {
+        Command::OpenSession { role, reply_to } => {
+            let _ = reply_to.send(Ok(manager.open_session(role)));
+        }
+        Command::Declare {
+            session,
+            decl,
+            reply_to,
+        } => queue_code_update(
+            manager,
+            queues,
+            session,
+            CodeUpdate::Declare(decl),
+            reply_to,
+        ),
+        Command::Update {
+            session,
+            decl,
+            reply_to,
+        } => queue_code_update(manager, queues, session, CodeUpdate::Update(decl), reply_to),
+        Command::Delete {
+            session,
+            name,
+            cascade,
+            reply_to,
+        } => queue_code_update(
+            manager,
+            queues,
+            session,
+            CodeUpdate::Delete { name, cascade },
+            reply_to,
+        ),
+        Command::Do {
+            session,
+            action,
+            reply_to,
+        } => {
+            /* Reject ill-typed actions before they are queued */
+            let checked = queues
+                .accepting()
+                .and_then(|_| manager.authorize(session, false))
+                .and_then(|_| ServiceManager::typecheck(&manager.decls, Some(&action)));
+            match checked {
+                Ok(_) => queues.actions.push(Queued::new(
+                    session,
+                    manager.queue_action(action),
+                    reply_to,
+                )),
+                Err(err_msg) => {
+                    let _ = reply_to.send(Err(err_msg));
+                }
+            }
+        }
+        Command::Read { name, reply_to } => {
+            let _ = reply_to.send(manager.read(&name).await);
+        }
+        Command::Subscribe {
+            name,
+            locker,
+            reply_to,
+        } => {
+            let subscription = manager.subscribe(&name).await;
+            if let (Ok(_), Some(locker)) = (&subscription, locker) {
+                manager.add_locker(&name, locker);
+            }
+            let _ = reply_to.send(subscription);
+        }
+        Command::RegisterMirror {
+            mirror,
+            peer,
+            member,
+            reply_to,
+        } => {
+            manager.register_mirror(&mirror, peer, &member);
+            let _ = reply_to.send(Ok(()));
+        }
+        Command::Lock {
+            txn,
+            name,
+            kind,
+            reply_to,
+        } => manager.locks.request(txn, &name, kind, reply_to),
+        Command::Release { txn } => manager.locks.release_all(txn),
+        Command::Metrics { reply_to } => {
+            let _ = reply_to.send(manager.metrics());
+        }
+        Command::AwaitQuiescent { reply_to } => queues.quiescence_waiters.push(reply_to),
+        Command::Shutdown { reply_to } => queues.shutdown_waiters.push(reply_to),
+        Command::SeedVersions { versions, reply_to } => {
+            manager.seed_versions(versions);
+            let _ = reply_to.send(Ok(()));
+        }
+        Command::Persist {
+            store,
+            srv,
+            reply_to,
+        } => {
+            manager.persist(store, &srv).await;
+            let _ = reply_to.send(Ok(()));
+        }
+    }
+}
+
+fn queue_code_update(
+    manager: &ServiceManager,
+    queues: &mut Queues,
+    session: SessionId,
+    update: CodeUpdate,
+    reply_to: Reply<()>,
+) {
+    match queues
+        .accepting()
+        .and_then(|_| manager.authorize(session, true))
+    {
+        Ok(()) => queues
+            .code_updates
+            .push(Queued::new(session, update, reply_to)),
+        Err(err_msg) => {
+            let _ = reply_to.send(Err(err_msg));
+        }
+    }
+}
+
+/* Runs one code update or action, picked at random, under the locks it
+ * needs, and tells the session that submitted it if it is aborted */
+async fn step(
+    manager: &mut ServiceManager,
+    queues: &mut Queues,
+    inbox: &mut mpsc::Receiver<Command>,
+) {
+    let pick_update = match (queues.code_updates.is_empty(), queues.actions.is_empty()) {
+        (false, true) => true,
+        (true, false) => false,
+        _ => rand::random::<bool>(),
+    };
+    if pick_update {
+        let idx = rand::thread_rng().gen_range(0..queues.code_updates.len());
+        let queued = queues.code_updates.swap_remove(idx);
+        let txn = queued.txn.unwrap_or_else(|| manager.txns.stamp());
+        let (local, elsewhere) = manager.update_lock_plan(&queued.item);
+        let held = match acquire_locks(manager, queues, inbox, txn, local, elsewhere).await {
+            Ok(held) => Some(held),
+            Err(reason) if queued.restarts < MAX_RESTARTS => {
+                info!(txn=%txn, reason=%reason, "srvmanager_proc > step > restart code update");
+                let restarts = queued.restarts + 1;
+                queues.code_updates.push(Queued {
+                    txn: Some(txn),
+                    restarts,
+                    ..queued
+                });
+                back_off(manager, queues, inbox, restarts).await;
+                return;
+            }
+            Err(_) => None,
+        };
+        let Queued {
+            session,
+            item: update,
+            reply_to,
+            ..
+        } = queued;
+        info!(session=%session, txn=%txn, update=?update, "srvmanager_proc > step > run code update");
+        let name = update.name();
+        let result = match held {
+            None => Err(format!("{} could not take its locks", txn)),
+            Some(_) => match update {
+                CodeUpdate::Declare(decl) => manager.declare(decl).await,
+                CodeUpdate::Update(decl) => manager.update(decl).await,
+                CodeUpdate::Delete { name, cascade } => manager.delete(&name, cascade).await,
+            },
+        };
+        settle_txn(manager, queues, inbox).await;
+        release_locks(manager, txn, held).await;
+        if result.is_ok() {
+            manager.save().await;
+        }
+        if let Err(reason) = &result {
+            let reason = reason.clone();
+            manager.notify(session, Notification::UpdateAborted { name, reason });
+        }
+        let _ = reply_to.send(result);
+    } else {
+        let idx = rand::thread_rng().gen_range(0..queues.actions.len());
+        let Queued {
+            session,
+            item: queued,
+            reply_to,
+            txn,
+            restarts,
+        } = queues.actions.swap_remove(idx);
+        let action = queued.action.clone();
+        let txn = txn.unwrap_or_else(|| manager.txns.stamp());
+        let result = match manager.dequeue_action(queued) {
+            Ok(checked) => {
+                let (local, elsewhere) = manager.action_lock_plan(&checked);
+                match acquire_locks(manager, queues, inbox, txn, local, elsewhere).await {
+                    Ok(held) => {
+                        let result = manager.run_action(checked).await;
+                        settle_txn(manager, queues, inbox).await;
+                        release_locks(manager, txn, Some(held)).await;
+                        result
+                    }
+                    Err(reason) if restarts < MAX_RESTARTS => {
+                        info!(txn=%txn, reason=%reason, "srvmanager_proc > step > restart action");
+                        let item = manager.queue_action(checked);
+                        queues.actions.push(Queued {
+                            session,
+                            item,
+                            reply_to,
+                            txn: Some(txn),
+                            restarts: restarts + 1,
+                        });
+                        back_off(manager, queues, inbox, restarts + 1).await;
+                        return;
+                    }
+                    Err(reason) => {
+                        release_locks(manager, txn, None).await;
+                        Err(reason)
+                    }
+                }
+            }
+            Err(err_msg) => Err(err_msg),
+        };
+        if result.is_ok() {
+            manager.save().await;
+        }
+        if let Err(reason) = &result {
+            let reason = reason.clone();
+            manager.notify(session, Notification::ActionAborted { action, reason });
+        }
+        let _ = reply_to.send(result);
+    }
+}
+
+/* Takes the local locks of `txn` and then those in other services. While
+ * it waits, commands are still served, so other transactions can take and
+ * release locks here. On failure, whatever was taken is released. */
+async fn acquire_locks(
+    manager: &mut ServiceManager,
+    queues: &mut Queues,
+    inbox: &mut mpsc::Receiver<Command>,
+    txn: TxnId,
+    local: Vec<(String, LockType)>,
+    elsewhere: Vec<LockTarget>,
+) -> Result<HeldElsewhere, String> {
+    let mut waits: Vec<oneshot::Receiver<Result<(), String>>> = vec![];
+    for (name, kind) in local.into_iter() {
+        match manager.locks.acquire(txn, &name, kind) {
+            Acquire::Granted => {}
+            Acquire::Wait => {
+                let (reply_to, reply) = oneshot::channel();
+                manager.locks.wait(txn, &name, kind, reply_to);
+                waits.push(reply);
+            }
+            Acquire::Die => {
+                manager.locks.release_all(txn);
+                return Err(format!("{} dies waiting for `{}`", txn, name));
+            }
+        }
+    }
+    let acquiring = async move {
+        for wait in waits.into_iter() {
+            wait.await
+                .unwrap_or_else(|_| Err(String::from("lock request dropped")))?;
+        }
+        lock::lock_elsewhere(txn, elsewhere).await
+    };
+    let held = serve_while(manager, queues, inbox, acquiring).await;
+    if held.is_err() {
+        manager.locks.release_all(txn);
+    }
+    held
+}
+
+/* Waits for the rounds of a transaction to settle before its locks are
+ * released, serving commands meanwhile. The defs downstream of what it
+ * wrote are locked until they have recomputed, or the next transaction
+ * would read their old values. */
+async fn settle_txn(
+    manager: &mut ServiceManager,
+    queues: &mut Queues,
+    inbox: &mut mpsc::Receiver<Command>,
+) {
+    let in_flight = manager.in_flight.clone();
+    serve_while(manager, queues, inbox, async move {
+        in_flight.quiescent().await
+    })
+    .await;
+}
+
+async fn release_locks(manager: &mut ServiceManager, txn: TxnId, held: Option<HeldElsewhere>) {
+    manager.locks.release_all(txn);
+    if let Some(held) = held {
+        held.release(txn).await;
+    }
+}
+
+/* Waits a little longer after every restart, so the transaction that made
+ * this one die can finish */
+async fn back_off(
+    manager: &mut ServiceManager,
+    queues: &mut Queues,
+    inbox: &mut mpsc::Receiver<Command>,
+    restarts: u32,
+) {
+    let millis = 1u64 << restarts.min(6);
+    serve_while(
+        manager,
+        queues,
+        inbox,
+        tokio::time::sleep(Duration::from_millis(millis)),
+    )
+    .await;
+}
+
+/* Serves commands until `fut` resolves */
+async fn serve_while<T>(
+    manager: &mut ServiceManager,
+    queues: &mut Queues,
+    inbox: &mut mpsc::Receiver<Command>,
+    fut: impl Future<Output = T>,
+) -> T {
+    tokio::pin!(fut);
+    loop {
+        tokio::select! {
+            out = &mut fut => return out,
+            Some(cmd) = inbox.recv() => accept_command(manager, queues, cmd).await,
+            (name, reason) = manager.next_death() => manager.restart_worker(&name, reason),
+        }
+    }
+}
+
+// TODO:
+// syntax abstraction
@@ -1,8 +1,74 @@
-use crate::backend::srvmanager_proc;
-use tokio::sync::mpsc;
+use crate::backend::message::{self, Message};
+use crate::backend::worker::{self, Remains, Worker, WorkerCore};
+use std::collections::HashMap;
+use tracing::info;
 
-pub async fn var_proc() {
-    loop {
-        todo!()
+/// A state var. It holds the value last written to it and depends on
+/// nothing, so it never waits on predecessors.
+pub struct VarWorker {
+    pub core: WorkerCore,
+}
+
+impl VarWorker {
+    pub fn new(core: WorkerCore) -> VarWorker {
+        VarWorker { core }
+    }
+
+    /// A var in place of one whose task panicked, holding the value the
+    /// dead one had. Its declaration is not evaluated again.
+    pub fn restore(mut core: WorkerCore, remains: &mut Remains) -> VarWorker {
+        core.take_over(remains);
+        VarWorker { core }
+    }
+}
+
+impl Worker for VarWorker {
+    fn core(&mut self) -> &mut WorkerCore {
+        &mut self.core
+    }
+
+    fn pending_reason(&self) -> Option<String> {
+        match self.core.curr_val {
+            Some(_) => None,
+            None => Some(String::from("not initialised yet")),
+        }
+    }
+
+    async fn handle_message(&mut self, msg: message::Message) {
+        match msg {
+            Message::InitVar {
+                var_name,
+                var_expr,
+                code_version,
+                value_version,
+                round,
+            } => {
+                self.core.name = var_name.clone();
+                self.core.version.code = code_version;
+                self.core.computing = Some(round.clone());
+                let val = worker::compute_val(&var_expr, &HashMap::new());
+                self.core.computing = None;
+                self.core.set_val(val);
+                if let Some(value_version) = value_version {
+                    self.core.version.value = value_version;
+                }
+                info!(name=%var_name, value=?self.core.curr_val, "varworker_proc > InitVar > computed");
+                self.core.send_to_succs(&round);
+                self.core.round_done(&round);
+            }
+            Message::WriteVar { new_val, round } => {
+                self.core.set_val(new_val);
+                self.core.send_to_succs(&round);
+                self.core.round_done(&round);
+            }
+            Message::RetrieveVal { reply_to } => {
+                self.core.apprise(reply_to, self.pending_reason(), false)
+            }
+            msg @ (Message::InitDef { .. } | Message::PredUpdatedTo { .. }) => {
+                let reason = format!("var `{}` does not depend on anything", self.core.name);
+                self.core.reject(msg, reason).await;
+            }
+            msg => self.core.handle_shared(msg).await,
+        }
     }
 }
@@ -0,0 +1,109 @@
+use crate::backend::lock::{LockType, TxnId};
+use crate::backend::message::{Val, Version};
+use serde::{Deserialize, Serialize};
+
+/// Bumped on every change to `WireMessage` that older peers cannot decode.
+/// Binary bodies depend on variant order, so new variants go at the end.
+pub const WIRE_VERSION: u16 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Encoding {
+    /// Human readable, for debugging.
+    Json,
+    /// Compact `bincode`, for production.
+    Binary,
+}
+
+impl Encoding {
+    fn tag(self) -> u8 {
+        match self {
+            Encoding::Json => 0,
+            Encoding::Binary => 1,
+        }
+    }
+
+    fn from_tag(tag: u8) -> Result<Encoding, String> {
+        match tag {
+            0 => Ok(Encoding::Json),
+            1 => Ok(Encoding::Binary),
+            _ => Err(format!("unknown encoding {}", tag)),
+        }
+    }
+}
+
+/// What services exchange across processes. Unlike `message::Message` it
+/// holds no channel handles or rounds, only names, values and versions.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum WireMessage {
+    /* Client to host */
+    Subscribe {
+        name: String,
+    },
+    /* Host to client */
+    PredUpdatedTo {
+        pred_name: String,
+        pred_value: Val,
+        pred_version: Version,
+    },
+    Error {
+        reason: String,
+    },
+    /* Client to host, for a client that mirrors `name`: the host locks the
+     * mirror through `LockRequest`s on the same connection */
+    SubscribeMirror {
+        name: String,
+    },
+    /* Either way: a service locking a name of the other on behalf of `txn`.
+     * `req` pairs the answer with its request. */
+    LockRequest {
+        req: u64,
+        txn: TxnId,
+        name: String,
+        kind: LockType,
+    },
+    LockGranted {
+        req: u64,
+    },
+    LockAborted {
+        req: u64,
+        reason: String,
+    },
+    ReleaseLocks {
+        txn: TxnId,
+    },
+}
+
+/// Encodes `msg` as a big-endian `u16` wire version, a one byte encoding
+/// tag and the body in that encoding.
+pub fn encode(msg: &WireMessage, encoding: Encoding) -> Result<Vec<u8>, String> {
+    let mut bytes = WIRE_VERSION.to_be_bytes().to_vec();
+    bytes.push(encoding.tag());
+    match encoding {
+        Encoding::Json => serde_json::to_writer(&mut bytes, msg).map_err(|err| err.to_string()),
+        Encoding::Binary => bincode::serialize_into(&mut bytes, msg).map_err(|err| err.to_string()),
+    }
+    .map_err(|err| format!("encode error: {}", err))?;
+    Ok(bytes)
+}
+
+/// Decodes what `encode` produced, along with the encoding the sender chose.
+pub fn decode(bytes: &[u8]) -> Result<(WireMessage, Encoding), String> {
+    if bytes.len() < 3 {
+        return Err(String::from("decode error: truncated header"));
+    }
+    let version = u16::from_be_bytes([bytes[0], bytes[1]]);
+    if version != WIRE_VERSION {
+        return Err(format!(
+            "decode error: wire version {} is not supported, expected {}",
+            version, WIRE_VERSION
+        ));
+    }
+    let encoding = Encoding::from_tag(bytes[2])?;
+    let body = &bytes[3..];
+    let msg = match encoding {
+        Encoding::Json => serde_json::from_slice(body).map_err(|err| err.to_string()),
+        Encoding::Binary => bincode::deserialize(body).map_err(|err| err.to_string()),
+    }
+    .map_err(|err| format!("decode error: {}", err))?;
+    Ok((msg, encoding))
+}
@@ -1,382 +1,789 @@
 use std::{
-    collections::{HashMap, HashSet},
+    any::Any,
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    future::Future,
+    mem,
     ops::Deref,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
 };
 
 use crate::{
-    backend::message,
+    backend::{
+        defworker_proc::PendingRound, message, metrics::Metrics, pool::Slot, quiescence::InFlight,
+    },
     frontend::meerast::{self, Expr},
 };
-use inline_colorization::*;
-use tokio::sync::mpsc;
-use tracing::info;
+use tokio::sync::{
+    mpsc::{self, error::TrySendError},
+    oneshot,
+};
+use tracing::{debug, info};
 
-pub struct Worker {
-    pub inbox: mpsc::Receiver<message::Message>,
-    pub sender_to_manager: mpsc::Sender<message::Message>,
-    pub senders_to_succs: Vec<mpsc::Sender<message::Message>>,
+/// What var and def workers share: the value they hold and the successors
+/// and clients that hear about it. `varworker_proc` and `defworker_proc`
+/// implement the kind-specific messages on top.
+pub trait Worker: Send + 'static {
+    fn core(&mut self) -> &mut WorkerCore;
+
+    /// Why the worker has no value yet, or why the one it has may not be
+    /// current.
+    fn pending_reason(&self) -> Option<String>;
+
+    fn handle_message(&mut self, msg: message::Message) -> impl Future<Output = ()> + Send;
+
+    /// Called once the inbox has closed.
+    fn abandon_rounds(&mut self) {}
+
+    /// Called before the first message, to go on with whatever a restarted
+    /// worker has taken over.
+    fn resume(&mut self) {}
+}
+
+pub async fn run_worker<W: Worker>(mut worker: W) {
+    worker.resume();
+    while let Some(msg) = worker.core().next_message().await {
+        worker.handle_message(msg).await;
+    }
+    worker.abandon_rounds();
+}
+
+/// Where a worker's messages wait: its own channel when it runs as a task
+/// of its own, or a queue that its shard fills when it runs in a `Pool`.
+#[derive(Debug)]
+pub enum Inbox {
+    Channel(mpsc::UnboundedReceiver<message::Message>),
+    Queue(VecDeque<message::Message>),
+}
+
+impl Inbox {
+    /// The next message, waiting for one on a channel. An empty queue is
+    /// only refilled between runs, so it yields `None` instead.
+    pub async fn recv(&mut self) -> Option<message::Message> {
+        match self {
+            Inbox::Channel(channel) => channel.recv().await,
+            Inbox::Queue(queue) => queue.pop_front(),
+        }
+    }
+
+    pub fn try_recv(&mut self) -> Option<message::Message> {
+        match self {
+            Inbox::Channel(channel) => channel.try_recv().ok(),
+            Inbox::Queue(queue) => queue.pop_front(),
+        }
+    }
+
+    pub(crate) fn push(&mut self, msg: message::Message) {
+        match self {
+            Inbox::Queue(queue) => queue.push_back(msg),
+            Inbox::Channel(_) => panic!("a worker with its own channel is not run by a pool"),
+        }
+    }
+}
+
+impl From<mpsc::UnboundedReceiver<message::Message>> for Inbox {
+    fn from(channel: mpsc::UnboundedReceiver<message::Message>) -> Inbox {
+        Inbox::Channel(channel)
+    }
+}
+
+/// How to reach a worker, whichever way it is run. Two addresses are the
+/// same worker if they are clones of each other.
+///
+/// Sending never waits: a worker blocked on a full successor while its own
+/// inbox fills up could stall the graph. Instead the manager holds back new
+/// writes while too many rounds are in flight, see `InFlight::below`.
+#[derive(Debug, Clone)]
+pub enum Addr {
+    Task(mpsc::UnboundedSender<message::Message>),
+    Pooled(Arc<Slot>),
+}
+
+impl Addr {
+    pub fn send(&self, msg: message::Message) -> Result<(), String> {
+        match self {
+            Addr::Task(sender) => sender.send(msg).map_err(|_| String::from("inbox closed")),
+            Addr::Pooled(slot) => slot.deliver(msg),
+        }
+    }
+
+    pub fn same_worker(&self, other: &Addr) -> bool {
+        match (self, other) {
+            (Addr::Task(a), Addr::Task(b)) => a.same_channel(b),
+            (Addr::Pooled(a), Addr::Pooled(b)) => Arc::ptr_eq(a, b),
+            _ => false,
+        }
+    }
+}
+
+/// What a worker leaves behind when it is dropped, which a panic in its
+/// task does as well. The manager restarts a worker that panicked from its
+/// declaration and its remains, so that nothing sent to it is lost.
+#[derive(Debug, Default)]
+pub struct Remains {
+    pub inbox: Option<Inbox>,
+    pub held_back: Option<message::Message>,
+    pub senders_to_succs: Vec<Subscriber>,
+    pub curr_val: Option<message::Val>,
+    pub version: message::Version,
+    pub computing: Option<message::Round>, /* What the worker died of */
+    /* Left by a def */
     pub replica: HashMap<String, message::Val>,
+    pub pending_rounds: BTreeMap<u64, PendingRound>,
+    pub last_read: HashSet<String>,
+}
+
+/// Where a worker leaves its `Remains`. It is shared with the manager, so
+/// it outlives the worker's task.
+#[derive(Debug, Default)]
+pub struct Checkpoint(Mutex<Remains>);
+
+impl Checkpoint {
+    /// Runs `f` on the remains. A panic cannot happen while they are held.
+    pub fn with<T>(&self, f: impl FnOnce(&mut Remains) -> T) -> T {
+        f(&mut self
+            .0
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner()))
+    }
+
+    pub fn take(&self) -> Remains {
+        self.with(mem::take)
+    }
+}
+
+/// How a worker ended, `reason` being set if it panicked.
+#[derive(Debug)]
+pub struct Death {
+    pub name: String,
+    pub checkpoint: Arc<Checkpoint>,
+    pub reason: Option<String>,
+}
+
+/// What a worker panicked with.
+pub fn panic_reason(payload: Box<dyn Any + Send>) -> String {
+    match payload.downcast::<String>() {
+        Ok(reason) => *reason,
+        Err(payload) => match payload.downcast::<&str>() {
+            Ok(reason) => reason.to_string(),
+            Err(_) => String::from("panicked"),
+        },
+    }
+}
+
+pub struct WorkerCore {
+    pub inbox: Inbox,
+    pub sender_to_manager: mpsc::Sender<message::Message>,
+    pub in_flight: Arc<InFlight>,
+    pub metrics: Arc<Metrics>,
+    pub senders_to_succs: Vec<Subscriber>,
     pub curr_val: Option<message::Val>,
-    pub def_expr: Option<meerast::Expr>, /* Is `Some` only for def's */
+    pub version: message::Version,
     pub name: String,
+    pub held_back: Option<message::Message>, /* Received while draining, handled next */
+    pub computing: Option<message::Round>,   /* Set while evaluating an expression */
+    pub checkpoint: Arc<Checkpoint>,
+    pub interest: Arc<Interest>,
 }
 
-impl Worker {
+impl Drop for WorkerCore {
+    fn drop(&mut self) {
+        let inbox = mem::replace(&mut self.inbox, Inbox::Queue(VecDeque::new()));
+        self.checkpoint.with(|remains| {
+            remains.inbox = Some(inbox);
+            remains.held_back = self.held_back.take();
+            remains.senders_to_succs = mem::take(&mut self.senders_to_succs);
+            remains.curr_val = self.curr_val.take();
+            remains.version = self.version;
+            remains.computing = self.computing.take();
+        });
+    }
+}
+
+/// Where a worker's updates go: a successor worker hears about every round
+/// it is affected by, a subscribed client only about new versions.
+#[derive(Debug)]
+pub enum Subscriber {
+    Succ(Addr, Arc<Interest>),
+    Client {
+        sender: mpsc::Sender<(message::Val, message::Version)>,
+        last_sent: Option<message::Version>,
+        backlog: Arc<Mutex<Backlog>>,
+    },
+}
+
+/// Whether a worker observes its predecessors, shared with them. A lazy def
+/// stops observing once it goes dormant, so lazy defs it reads can go
+/// dormant as well. The manager pins the defs it wakes, which keeps them
+/// observing until they have computed.
+#[derive(Debug)]
+pub struct Interest {
+    live: AtomicBool,
+    pins: AtomicUsize,
+}
+
+impl Default for Interest {
+    fn default() -> Self {
+        Interest {
+            live: AtomicBool::new(true),
+            pins: AtomicUsize::new(0),
+        }
+    }
+}
+
+impl Interest {
+    pub fn observing(&self) -> bool {
+        self.live.load(Ordering::SeqCst) || self.pinned()
+    }
+
+    pub fn pinned(&self) -> bool {
+        self.pins.load(Ordering::SeqCst) > 0
+    }
+
+    pub fn set_live(&self, live: bool) {
+        self.live.store(live, Ordering::SeqCst);
+    }
+
+    pub fn pin(&self) {
+        self.pins.fetch_add(1, Ordering::SeqCst);
+    }
+
+    pub fn unpin(&self) {
+        self.pins.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// The value a client that has fallen behind is sent once its channel has
+/// room again. Only the latest one is kept.
+#[derive(Debug, Default)]
+pub struct Backlog {
+    latest: Option<(message::Val, message::Version)>,
+    delivering: bool, /* A task is waiting for room to send `latest` */
+}
+
+impl WorkerCore {
     pub fn new(
-        inbox: mpsc::Receiver<message::Message>,
+        inbox: impl Into<Inbox>,
         sender_to_manager: mpsc::Sender<message::Message>,
-        senders_to_succs: Vec<mpsc::Sender<message::Message>>,
+        in_flight: Arc<InFlight>,
+        metrics: Arc<Metrics>,
         name: &str,
-    ) -> Worker {
-        Worker {
-            inbox,
+    ) -> WorkerCore {
+        WorkerCore {
+            inbox: inbox.into(),
             sender_to_manager,
-            senders_to_succs,
-            replica: HashMap::new(),
+            in_flight,
+            metrics,
+            senders_to_succs: vec![],
             curr_val: None,
-            def_expr: None,
+            version: message::Version::default(),
             name: name.to_string(),
+            held_back: None,
+            computing: None,
+            checkpoint: Arc::new(Checkpoint::default()),
+            interest: Arc::default(),
         }
     }
 
-    #[tracing::instrument]
-    pub async fn handle_message(
-        sender_to_manager: &mpsc::Sender<message::Message>,
-        senders_to_succs: &mut Vec<mpsc::Sender<message::Message>>,
-        replica: &mut HashMap<String, message::Val>,
-        curr_val: &mut Option<message::Val>,
-        def_expr: &mut Option<meerast::Expr>,
-        name: &mut String,
-        msg: &message::Message,
-    ) {
-        info!(
-            replica=?replica,
-            curr_val=?curr_val,
-            def_expr=?def_expr,
-            name=%name,
-            msg=?msg,
-            "worker > handle_message called",
-        );
+    /// Takes over what the dead worker of `remains` has left, but its
+    /// inbox. The value it was computing when it died is given up: the
+    /// round is reported with the value it had before.
+    pub fn take_over(&mut self, remains: &mut Remains) {
+        self.held_back = remains.held_back.take();
+        self.senders_to_succs = mem::take(&mut remains.senders_to_succs);
+        self.curr_val = remains.curr_val.take();
+        self.version.value = remains.version.value;
+        if let Some(round) = remains.computing.take() {
+            self.send_to_succs(&round);
+            self.round_done(&round);
+        }
+    }
+
+    pub async fn next_message(&mut self) -> Option<message::Message> {
+        match self.held_back.take() {
+            Some(msg) => Some(msg),
+            None => self.inbox.recv().await,
+        }
+    }
+
+    /// Like `next_message`, but never waits.
+    pub fn next_ready(&mut self) -> Option<message::Message> {
+        self.held_back.take().or_else(|| self.inbox.try_recv())
+    }
+
+    /// Handles the messages every kind of worker understands, and rejects
+    /// the rest.
+    pub async fn handle_shared(&mut self, msg: message::Message) {
         match msg {
-            message::Message::InitVar { var_name, var_expr } => {
-                *name = var_name.clone();
-                *curr_val = Some(Worker::compute_val(var_expr, replica));
+            message::Message::AddSenderToSucc {
+                sender,
+                interest,
+                round,
+            } => {
+                /* Adding a successor again only resends the current value */
+                let known = self.senders_to_succs.iter().any(|subscriber| {
+                    matches!(subscriber, Subscriber::Succ(succ, _) if succ.same_worker(&sender))
+                });
+                if !known {
+                    self.senders_to_succs
+                        .push(Subscriber::Succ(sender.clone(), interest));
+                }
+                let _ = sender.send(message::Message::PredUpdatedTo {
+                    pred_name: self.name.clone(),
+                    pred_value: self.curr_val.clone(),
+                    pred_version: self.version,
+                    round: round.clone(),
+                    superseded: false,
+                });
+                self.round_done(&round);
+            }
+            message::Message::RemoveSenderToSucc { sender } => {
+                self.senders_to_succs.retain(|subscriber| match subscriber {
+                    Subscriber::Succ(succ, _) => !succ.same_worker(&sender),
+                    Subscriber::Client { .. } => true,
+                });
+            }
+            message::Message::Subscribe { sender } => {
+                self.interest.set_live(true);
+                self.senders_to_succs.push(Subscriber::Client {
+                    sender,
+                    last_sent: None,
+                    backlog: Arc::default(),
+                });
+                self.notify_clients();
+            }
+            msg => {
+                let reason = format!("`{}` cannot handle {:?}", self.name, msg);
+                self.reject(msg, reason).await;
+            }
+        }
+    }
+
+    pub fn apprise(
+        &self,
+        reply_to: oneshot::Sender<message::Message>,
+        pending: Option<String>,
+        dormant: bool,
+    ) {
+        let _ = reply_to.send(message::Message::AppriseVal {
+            worker_name: self.name.clone(),
+            worker_value: self.curr_val.clone(),
+            worker_version: self.version,
+            pending,
+            dormant,
+        });
+    }
+
+    /// Reports a message this worker cannot handle to the manager instead of
+    /// acting on it. A round it carries still counts as done here, so
+    /// quiescence is not held up.
+    pub async fn reject(&mut self, msg: message::Message, reason: String) {
+        info!(name=%self.name, reason=%reason, "worker > reject");
+        match &msg {
+            message::Message::InitVar { round, .. }
+            | message::Message::InitDef { round, .. }
+            | message::Message::WriteVar { round, .. }
+            | message::Message::AddSenderToSucc { round, .. }
+            | message::Message::PredUpdatedTo { round, .. } => self.round_done(round),
+            _ => {}
+        }
+        let _ = self
+            .sender_to_manager
+            .send(message::Message::Rejected {
+                worker_name: self.name.clone(),
+                reason,
+            })
+            .await;
+    }
 
+    /// Whether the worker is pinned, or a client or a successor that is
+    /// not dormant still listens to it. Clients whose subscription has been
+    /// dropped are forgotten.
+    pub fn observed(&mut self) -> bool {
+        self.senders_to_succs.retain(|subscriber| match subscriber {
+            Subscriber::Succ(..) => true,
+            Subscriber::Client { sender, .. } => !sender.is_closed(),
+        });
+        self.interest.pinned()
+            || self
+                .senders_to_succs
+                .iter()
+                .any(|subscriber| match subscriber {
+                    Subscriber::Succ(_, interest) => interest.observing(),
+                    Subscriber::Client { .. } => true,
+                })
+    }
+
+    pub fn round_done(&self, round: &message::Round) {
+        /* A predecessor that has just been cut off by an update may still
+         * deliver rounds this worker is no longer part of. */
+        if round.affected.contains(&self.name) {
+            self.in_flight.finish(round);
+        }
+    }
+
+    pub fn set_val(&mut self, new_val: message::Val) {
+        if self.curr_val.as_ref() != Some(&new_val) {
+            self.curr_val = Some(new_val);
+            self.version.value += 1;
+        }
+    }
+
+    pub fn send_to_succs(&mut self, round: &message::Round) {
+        self.report_round(round, false);
+        self.notify_clients();
+    }
+
+    /// Tells successors that this worker has skipped `round` in favour of a
+    /// later one. Clients are not told anything.
+    pub fn send_superseded(&mut self, round: &message::Round) {
+        self.report_round(round, true);
+    }
+
+    fn report_round(&self, round: &message::Round, superseded: bool) {
+        for subscriber in self.senders_to_succs.iter() {
+            if let Subscriber::Succ(succ, _) = subscriber {
                 let msg = message::Message::PredUpdatedTo {
-                    pred_name: name.clone(),
-                    pred_value: curr_val.clone(),
+                    pred_name: self.name.clone(),
+                    pred_value: self.curr_val.clone(),
+                    pred_version: self.version,
+                    round: round.clone(),
+                    superseded,
                 };
-                println!(
-                    "{color_red}InitVar, call compute val for\nvar {}\nsend {:?}{color_reset}\n",
-                    var_name, msg,
-                );
-                for succ in senders_to_succs.iter() {
-                    let _ = succ.send(msg.clone()).await;
+                let _ = succ.send(msg);
+            }
+        }
+    }
+
+    /// Sends the current value to every client that has not seen this
+    /// version yet, and drops clients whose subscription has been dropped.
+    fn notify_clients(&mut self) {
+        let curr_val = match &self.curr_val {
+            Some(v) => v,
+            None => return,
+        };
+        let version = self.version;
+        self.senders_to_succs
+            .retain_mut(|subscriber| match subscriber {
+                Subscriber::Succ(..) => true,
+                Subscriber::Client {
+                    sender,
+                    last_sent,
+                    backlog,
+                } => {
+                    if *last_sent == Some(version) {
+                        return true;
+                    }
+                    *last_sent = Some(version);
+                    notify_client(sender, backlog, (curr_val.clone(), version))
                 }
+            });
+    }
+}
+
+/* Sends `update` to a client, or leaves it in the client's backlog if the
+ * client has fallen behind. Such a client misses intermediate values but is
+ * sent the latest one as soon as it has room. Returns whether the client is
+ * still there. */
+fn notify_client(
+    sender: &mpsc::Sender<(message::Val, message::Version)>,
+    backlog: &Arc<Mutex<Backlog>>,
+    update: (message::Val, message::Version),
+) -> bool {
+    if sender.is_closed() {
+        return false;
+    }
+    let mut pending = backlog
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    if pending.delivering {
+        pending.latest = Some(update);
+        return true;
+    }
+    match sender.try_send(update) {
+        Ok(()) => true,
+        Err(TrySendError::Full(update)) => {
+            pending.latest = Some(update);
+            pending.delivering = true;
+            tokio::spawn(deliver_backlog(sender.clone(), backlog.clone()));
+            true
+        }
+        Err(TrySendError::Closed(_)) => false,
+    }
+}
+
+async fn deliver_backlog(
+    sender: mpsc::Sender<(message::Val, message::Version)>,
+    backlog: Arc<Mutex<Backlog>>,
+) {
+    while let Ok(permit) = sender.reserve().await {
+        let mut pending = backlog
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        match pending.latest.take() {
+            Some(update) => permit.send(update),
+            None => {
+                pending.delivering = false;
+                return;
             }
-            message::Message::InitDef {
-                def_name,
-                def_expr: def_val,
-            } => {
-                *name = def_name.clone();
-                *def_expr = Some(def_val.clone());
-                *curr_val = Some(Worker::compute_val(def_val, replica));
+        }
+    }
+}
 
-                let msg = message::Message::PredUpdatedTo {
-                    pred_name: name.clone(),
-                    pred_value: curr_val.clone(),
+pub fn compute_val(expr: &meerast::Expr, replica: &HashMap<String, message::Val>) -> message::Val {
+    compute_val_reading(expr, replica, &mut HashSet::new())
+}
+
+/// Like `compute_val`, also adding to `reads` every replica entry the
+/// evaluation actually looked at. Only the taken branch of an `if` counts.
+pub fn compute_val_reading(
+    expr: &meerast::Expr,
+    replica: &HashMap<String, message::Val>,
+    reads: &mut HashSet<String>,
+) -> message::Val {
+    match expr {
+        meerast::Expr::IdExpr { ident } => {
+            reads.insert(ident.clone());
+            debug!(ident=%ident, replica=?replica, "worker > compute_val > identifier");
+            match replica.get(ident) {
+                Some(val) => val.clone(),
+                None => panic!("`{}` is read before it has a value", ident),
+            }
+        }
+        meerast::Expr::IntConst { val } => message::Val::Int(val.clone()),
+        meerast::Expr::BoolConst { val } => message::Val::Bool(val.clone()),
+        meerast::Expr::Action { stmt: _ } => message::Val::Action(expr.clone()),
+        meerast::Expr::Member {
+            srv_name: _,
+            member: _,
+        } => panic!(),
+        meerast::Expr::Apply { fun, args } => {
+            let fun = match compute_val_reading(fun, replica, reads) {
+                message::Val::Lambda(lambda) => lambda,
+                _ => panic!("this indicates typechecking bugs"),
+            };
+            let substed_fun_body = subst_pars_with_args(&fun, args);
+            compute_val_reading(&substed_fun_body, replica, reads)
+        }
+        meerast::Expr::BopExpr { opd1, opd2, bop } => match bop {
+            meerast::Binop::Add => {
+                let opd1_val = match compute_val_reading(opd1, replica, reads) {
+                    message::Val::Int(i) => i,
+                    _ => panic!("this indicates typechecking bugs"),
                 };
-                println!(
-                    "{color_red}InitDef, call compute val for\ndef {}\nsend{:?}{color_reset}\n",
-                    def_name, msg,
-                );
-                for succ in senders_to_succs.iter() {
-                    let _ = succ.send(msg.clone()).await;
-                }
+                let opd2_val = match compute_val_reading(opd2, replica, reads) {
+                    message::Val::Int(i) => i,
+                    _ => panic!("this indicates typechecking bugs"),
+                };
+                message::Val::Int(opd1_val + opd2_val)
             }
-            message::Message::AddSenderToSucc { sender } => {
-                senders_to_succs.push(sender.clone());
-                let _ = sender
-                    .send(message::Message::PredUpdatedTo {
-                        pred_name: name.clone(),
-                        pred_value: curr_val.clone(),
-                    })
-                    .await;
+            meerast::Binop::Sub => {
+                let opd1_val = match compute_val_reading(opd1, replica, reads) {
+                    message::Val::Int(i) => i,
+                    _ => panic!("this indicates typechecking bugs"),
+                };
+                let opd2_val = match compute_val_reading(opd2, replica, reads) {
+                    message::Val::Int(i) => i,
+                    _ => panic!("this indicates typechecking bugs"),
+                };
+                message::Val::Int(opd1_val - opd2_val)
             }
-            message::Message::RetrieveVal => {
-                let _ = sender_to_manager
-                    .send(message::Message::AppriseVal {
-                        worker_name: name.clone(),
-                        worker_value: curr_val.clone(),
-                    })
-                    .await;
+            meerast::Binop::Mul => {
+                let opd1_val = match compute_val_reading(opd1, replica, reads) {
+                    message::Val::Int(i) => i,
+                    _ => panic!("this indicates typechecking bugs"),
+                };
+                let opd2_val = match compute_val_reading(opd2, replica, reads) {
+                    message::Val::Int(i) => i,
+                    _ => panic!("this indicates typechecking bugs"),
+                };
+                message::Val::Int(opd1_val * opd2_val)
             }
-            message::Message::AppriseVal {
-                worker_name: _,
-                worker_value: _,
-            } => {
-                panic!("worker should not receive `AppriseVal` message");
+            meerast::Binop::Div => {
+                let opd1_val = match compute_val_reading(opd1, replica, reads) {
+                    message::Val::Int(i) => i,
+                    _ => panic!("this indicates typechecking bugs"),
+                };
+                let opd2_val = match compute_val_reading(opd2, replica, reads) {
+                    message::Val::Int(i) => i,
+                    _ => panic!("this indicates typechecking bugs"),
+                };
+                message::Val::Int(opd1_val / opd2_val)
             }
-            message::Message::PredUpdatedTo {
-                pred_name,
-                pred_value,
-            } => {
-                if let Some(pred_value) = pred_value {
-                    replica.insert(pred_name.clone(), pred_value.clone());
-                    /* Re-evaluate curr_val */
-                    *curr_val = Some(Worker::compute_val(
-                        match def_expr {
-                            Some(e) => e,
-                            None => panic!(),
-                        },
-                        replica,
-                    ));
-                }
+            meerast::Binop::Eq => {
+                let opd1_val = match compute_val_reading(opd1, replica, reads) {
+                    message::Val::Int(i) => i,
+                    _ => panic!("this indicates typechecking bugs"),
+                };
+                let opd2_val = match compute_val_reading(opd2, replica, reads) {
+                    message::Val::Int(i) => i,
+                    _ => panic!("this indicates typechecking bugs"),
+                };
+                message::Val::Bool(opd1_val == opd2_val)
+            }
+            meerast::Binop::Lt => {
+                let opd1_val = match compute_val_reading(opd1, replica, reads) {
+                    message::Val::Int(i) => i,
+                    _ => panic!("this indicates typechecking bugs"),
+                };
+                let opd2_val = match compute_val_reading(opd2, replica, reads) {
+                    message::Val::Int(i) => i,
+                    _ => panic!("this indicates typechecking bugs"),
+                };
+                message::Val::Bool(opd1_val < opd2_val)
+            }
+            meerast::Binop::Gt => {
+                let opd1_val = match compute_val_reading(opd1, replica, reads) {
+                    message::Val::Int(i) => i,
+                    _ => panic!("this indicates typechecking bugs"),
+                };
+                let opd2_val = match compute_val_reading(opd2, replica, reads) {
+                    message::Val::Int(i) => i,
+                    _ => panic!("this indicates typechecking bugs"),
+                };
+                message::Val::Bool(opd1_val > opd2_val)
+            }
+            meerast::Binop::And => {
+                let opd1_val = match compute_val_reading(opd1, replica, reads) {
+                    message::Val::Bool(b) => b,
+                    _ => panic!("this indicates typechecking bugs"),
+                };
+                let opd2_val = match compute_val_reading(opd2, replica, reads) {
+                    message::Val::Bool(b) => b,
+                    _ => panic!("this indicates typechecking bugs"),
+                };
+                message::Val::Bool(opd1_val && opd2_val)
+            }
+            meerast::Binop::Or => {
+                let opd1_val = match compute_val_reading(opd1, replica, reads) {
+                    message::Val::Bool(b) => b,
+                    _ => panic!("this indicates typechecking bugs"),
+                };
+                let opd2_val = match compute_val_reading(opd2, replica, reads) {
+                    message::Val::Bool(b) => b,
+                    _ => panic!("this indicates typechecking bugs"),
+                };
+                message::Val::Bool(opd1_val || opd2_val)
+            }
+        },
+        meerast::Expr::UopExpr { opd, uop } => match uop {
+            meerast::Uop::Neg => {
+                let opd_val = match compute_val_reading(opd, replica, reads) {
+                    message::Val::Int(i) => i,
+                    _ => panic!("this indicates typechecking bugs"),
+                };
+                message::Val::Int(-opd_val)
+            }
+            meerast::Uop::Not => {
+                let opd_val = match compute_val_reading(opd, replica, reads) {
+                    message::Val::Bool(b) => b,
+                    _ => panic!("this indicates typechecking bugs"),
+                };
+                message::Val::Bool(!opd_val)
+            }
+        },
+        meerast::Expr::IfExpr { cond, then, elze } => {
+            let cond = match compute_val_reading(cond, replica, reads) {
+                message::Val::Bool(b) => b,
+                _ => panic!(),
+            };
+            if cond {
+                compute_val_reading(then, replica, reads)
+            } else {
+                compute_val_reading(elze, replica, reads)
             }
         }
+        meerast::Expr::Lambda { pars: _, body: _ } => message::Val::Lambda(expr.clone()),
     }
+}
 
-    pub fn compute_val(
-        expr: &meerast::Expr,
-        replica: &HashMap<String, message::Val>,
-    ) -> message::Val {
+pub fn subst_pars_with_args(fun: &meerast::Expr, args: &Vec<meerast::Expr>) -> meerast::Expr {
+    fn subst(expr: &mut meerast::Expr, ident_expr_map: &HashMap<String, &meerast::Expr>) {
         match expr {
             meerast::Expr::IdExpr { ident } => {
-                println!(
-                    "{color_green}identifier expr {}, replica: {:?}{color_reset}\n",
-                    ident, replica,
-                );
-                replica.get(ident).expect("").clone()
+                let ent = ident_expr_map.get(ident);
+                match ent {
+                    Some(e) => *expr = e.deref().clone(),
+                    None => {}
+                }
+            }
+            meerast::Expr::IntConst { val: _ } | meerast::Expr::BoolConst { val: _ } => {}
+            meerast::Expr::Action { stmt } => {
+                let sgls = match stmt {
+                    meerast::Stmt::Stmt { sgl_stmts } => sgl_stmts,
+                };
+                for sgl_stmt in sgls.iter_mut() {
+                    match sgl_stmt {
+                        meerast::SglStmt::Do { act } => {
+                            subst(act, ident_expr_map);
+                        }
+                        meerast::SglStmt::Ass { dst: _, src } => {
+                            subst(src, ident_expr_map);
+                        }
+                    }
+                }
             }
-            meerast::Expr::IntConst { val } => message::Val::Int(val.clone()),
-            meerast::Expr::BoolConst { val } => message::Val::Bool(val.clone()),
-            meerast::Expr::Action { stmt: _ } => message::Val::Action(expr.clone()),
             meerast::Expr::Member {
                 srv_name: _,
                 member: _,
             } => panic!(),
             meerast::Expr::Apply { fun, args } => {
-                let substed_fun_body = Worker::subst_pars_with_args(fun, args);
-                Worker::compute_val(&substed_fun_body, replica)
-            }
-            meerast::Expr::BopExpr { opd1, opd2, bop } => match bop {
-                meerast::Binop::Add => {
-                    let opd1_val = match Worker::compute_val(opd1, replica) {
-                        message::Val::Int(i) => i,
-                        _ => panic!("this indicates typechecking bugs"),
-                    };
-                    let opd2_val = match Worker::compute_val(opd2, replica) {
-                        message::Val::Int(i) => i,
-                        _ => panic!("this indicates typechecking bugs"),
-                    };
-                    message::Val::Int(opd1_val + opd2_val)
-                }
-                meerast::Binop::Sub => {
-                    let opd1_val = match Worker::compute_val(opd1, replica) {
-                        message::Val::Int(i) => i,
-                        _ => panic!("this indicates typechecking bugs"),
-                    };
-                    let opd2_val = match Worker::compute_val(opd2, replica) {
-                        message::Val::Int(i) => i,
-                        _ => panic!("this indicates typechecking bugs"),
-                    };
-                    message::Val::Int(opd1_val - opd2_val)
-                }
-                meerast::Binop::Mul => {
-                    let opd1_val = match Worker::compute_val(opd1, replica) {
-                        message::Val::Int(i) => i,
-                        _ => panic!("this indicates typechecking bugs"),
-                    };
-                    let opd2_val = match Worker::compute_val(opd2, replica) {
-                        message::Val::Int(i) => i,
-                        _ => panic!("this indicates typechecking bugs"),
-                    };
-                    message::Val::Int(opd1_val * opd2_val)
-                }
-                meerast::Binop::Div => {
-                    let opd1_val = match Worker::compute_val(opd1, replica) {
-                        message::Val::Int(i) => i,
-                        _ => panic!("this indicates typechecking bugs"),
-                    };
-                    let opd2_val = match Worker::compute_val(opd2, replica) {
-                        message::Val::Int(i) => i,
-                        _ => panic!("this indicates typechecking bugs"),
-                    };
-                    message::Val::Int(opd1_val / opd2_val)
-                }
-                meerast::Binop::Eq => {
-                    let opd1_val = match Worker::compute_val(opd1, replica) {
-                        message::Val::Int(i) => i,
-                        _ => panic!("this indicates typechecking bugs"),
-                    };
-                    let opd2_val = match Worker::compute_val(opd2, replica) {
-                        message::Val::Int(i) => i,
-                        _ => panic!("this indicates typechecking bugs"),
-                    };
-                    message::Val::Bool(opd1_val == opd2_val)
-                }
-                meerast::Binop::Lt => {
-                    let opd1_val = match Worker::compute_val(opd1, replica) {
-                        message::Val::Int(i) => i,
-                        _ => panic!("this indicates typechecking bugs"),
-                    };
-                    let opd2_val = match Worker::compute_val(opd2, replica) {
-                        message::Val::Int(i) => i,
-                        _ => panic!("this indicates typechecking bugs"),
-                    };
-                    message::Val::Bool(opd1_val < opd2_val)
-                }
-                meerast::Binop::Gt => {
-                    let opd1_val = match Worker::compute_val(opd1, replica) {
-                        message::Val::Int(i) => i,
-                        _ => panic!("this indicates typechecking bugs"),
-                    };
-                    let opd2_val = match Worker::compute_val(opd2, replica) {
-                        message::Val::Int(i) => i,
-                        _ => panic!("this indicates typechecking bugs"),
-                    };
-                    message::Val::Bool(opd1_val > opd2_val)
+                subst(fun, ident_expr_map);
+                for apply_arg in args.iter_mut() {
+                    subst(apply_arg, ident_expr_map);
                 }
-                meerast::Binop::And => {
-                    let opd1_val = match Worker::compute_val(opd1, replica) {
-                        message::Val::Bool(b) => b,
-                        _ => panic!("this indicates typechecking bugs"),
-                    };
-                    let opd2_val = match Worker::compute_val(opd2, replica) {
-                        message::Val::Bool(b) => b,
-                        _ => panic!("this indicates typechecking bugs"),
-                    };
-                    message::Val::Bool(opd1_val && opd2_val)
-                }
-                meerast::Binop::Or => {
-                    let opd1_val = match Worker::compute_val(opd1, replica) {
-                        message::Val::Bool(b) => b,
-                        _ => panic!("this indicates typechecking bugs"),
-                    };
-                    let opd2_val = match Worker::compute_val(opd2, replica) {
-                        message::Val::Bool(b) => b,
-                        _ => panic!("this indicates typechecking bugs"),
-                    };
-                    message::Val::Bool(opd1_val || opd2_val)
-                }
-            },
-            meerast::Expr::UopExpr { opd, uop } => match uop {
-                meerast::Uop::Neg => {
-                    let opd_val = match Worker::compute_val(opd, replica) {
-                        message::Val::Int(i) => i,
-                        _ => panic!("this indicates typechecking bugs"),
-                    };
-                    message::Val::Int(-opd_val)
-                }
-                meerast::Uop::Not => {
-                    let opd_val = match Worker::compute_val(opd, replica) {
-                        message::Val::Bool(b) => b,
-                        _ => panic!("this indicates typechecking bugs"),
-                    };
-                    message::Val::Bool(!opd_val)
-                }
-            },
+            }
+            meerast::Expr::BopExpr { opd1, opd2, bop } => {
+                subst(opd1, ident_expr_map);
+                subst(opd2, ident_expr_map);
+            }
+            meerast::Expr::UopExpr { opd, uop } => {
+                subst(opd, ident_expr_map);
+            }
             meerast::Expr::IfExpr { cond, then, elze } => {
-                let cond = match Worker::compute_val(cond, replica) {
-                    message::Val::Bool(b) => b,
-                    _ => panic!(),
-                };
-                if cond {
-                    Worker::compute_val(then, replica)
-                } else {
-                    Worker::compute_val(elze, replica)
-                }
+                subst(cond, ident_expr_map);
+                subst(then, ident_expr_map);
+                subst(elze, ident_expr_map);
             }
-            meerast::Expr::Lambda { pars: _, body: _ } => message::Val::Lambda(expr.clone()),
-        }
-    }
-
-    pub fn subst_pars_with_args(fun: &meerast::Expr, args: &Vec<meerast::Expr>) -> meerast::Expr {
-        fn subst(expr: &mut meerast::Expr, ident_expr_map: &HashMap<String, &meerast::Expr>) {
-            match expr {
-                meerast::Expr::IdExpr { ident } => {
-                    let ent = ident_expr_map.get(ident);
-                    match ent {
-                        Some(e) => *expr = e.deref().clone(),
-                        None => {}
-                    }
-                }
-                meerast::Expr::IntConst { val: _ } | meerast::Expr::BoolConst { val: _ } => {}
-                meerast::Expr::Action { stmt } => {
-                    let sgls = match stmt {
-                        meerast::Stmt::Stmt { sgl_stmts } => sgl_stmts,
+            meerast::Expr::Lambda { pars, body } => {
+                let mut par_names: HashSet<String> = HashSet::new();
+                for par in pars.iter() {
+                    let name = match par {
+                        meerast::Expr::IdExpr { ident } => ident.clone(),
+                        _ => panic!(),
                     };
-                    for sgl_stmt in sgls.iter_mut() {
-                        match sgl_stmt {
-                            meerast::SglStmt::Do { act } => {
-                                subst(act, ident_expr_map);
-                            }
-                            meerast::SglStmt::Ass { dst: _, src } => {
-                                subst(src, ident_expr_map);
-                            }
-                        }
-                    }
-                }
-                meerast::Expr::Member {
-                    srv_name: _,
-                    member: _,
-                } => panic!(),
-                meerast::Expr::Apply { fun, args } => {
-                    subst(fun, ident_expr_map);
-                    for apply_arg in args.iter_mut() {
-                        subst(apply_arg, ident_expr_map);
-                    }
-                }
-                meerast::Expr::BopExpr { opd1, opd2, bop } => {
-                    subst(opd1, ident_expr_map);
-                    subst(opd2, ident_expr_map);
+                    par_names.insert(name);
                 }
-                meerast::Expr::UopExpr { opd, uop } => {
-                    subst(opd, ident_expr_map);
-                }
-                meerast::Expr::IfExpr { cond, then, elze } => {
-                    subst(cond, ident_expr_map);
-                    subst(then, ident_expr_map);
-                    subst(elze, ident_expr_map);
-                }
-                meerast::Expr::Lambda { pars, body } => {
-                    let mut par_names: HashSet<String> = HashSet::new();
-                    for par in pars.iter() {
-                        let name = match par {
-                            meerast::Expr::IdExpr { ident } => ident.clone(),
-                            _ => panic!(),
-                        };
-                        par_names.insert(name);
-                    }
-                    let mut body_map: HashMap<String, &meerast::Expr> = HashMap::new();
-                    for (ident, arg_expr) in ident_expr_map.iter() {
-                        if !par_names.contains(ident) {
-                            body_map.insert(ident.clone(), arg_expr.deref());
-                        }
+                let mut body_map: HashMap<String, &meerast::Expr> = HashMap::new();
+                for (ident, arg_expr) in ident_expr_map.iter() {
+                    if !par_names.contains(ident) {
+                        body_map.insert(ident.clone(), arg_expr.deref());
                     }
-                    subst(body, &body_map);
                 }
+                subst(body, &body_map);
             }
         }
+    }
 
-        let pars = match fun {
-            meerast::Expr::Lambda { pars: ps, body: _ } => ps,
-            _ => panic!(),
-        };
-        let body = match fun {
-            meerast::Expr::Lambda { pars: _, body: bd } => bd.deref(),
+    let pars = match fun {
+        meerast::Expr::Lambda { pars: ps, body: _ } => ps,
+        _ => panic!(),
+    };
+    let body = match fun {
+        meerast::Expr::Lambda { pars: _, body: bd } => bd.deref(),
+        _ => panic!(),
+    };
+    let mut par_arg_map: HashMap<String, &meerast::Expr> = HashMap::new();
+    for (par, arg) in std::iter::zip(pars.iter(), args.iter()) {
+        let par_ident = match par {
+            meerast::Expr::IdExpr { ident } => ident.clone(),
             _ => panic!(),
         };
-        let mut par_arg_map: HashMap<String, &meerast::Expr> = HashMap::new();
-        for (par, arg) in std::iter::zip(pars.iter(), args.iter()) {
-            let par_ident = match par {
-                meerast::Expr::IdExpr { ident } => ident.clone(),
-                _ => panic!(),
-            };
-            par_arg_map.insert(par_ident, arg);
-        }
-        let mut substed_expr: Expr = body.clone();
-        subst(&mut substed_expr, &par_arg_map);
-        substed_expr
+        par_arg_map.insert(par_ident, arg);
     }
+    let mut substed_expr: Expr = body.clone();
+    subst(&mut substed_expr, &par_arg_map);
+    substed_expr
 }
@@ -1,33 +1,35 @@
+use serde::{Deserialize, Serialize};
 use std::fmt::Display;
 
 trait AstNode {}
 
 impl AstNode for ReplInput {}
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum ReplInput {
     Service(Service),
     Do(SglStmt),
     Decl(Decl),
     Update(Decl),
+    Delete { name: String, cascade: bool },
     Open(String),
     Close,
     Exit,
 }
 
 impl AstNode for Program {}
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum Program {
     Prog { services: Vec<Service> },
 }
 
 impl AstNode for Service {}
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum Service {
     Srv { name: String, decls: Vec<Decl> },
 }
 
 impl AstNode for Decl {}
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum Decl {
     Import {
         srv_name: String,
@@ -40,24 +42,27 @@ pub enum Decl {
         name: String,
         val: Expr,
         is_pub: bool,
+        /* Computed only while something reads or subscribes to it */
+        #[serde(default)]
+        is_lazy: bool,
     },
 }
 
 impl AstNode for Stmt {}
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum Stmt {
     Stmt { sgl_stmts: Vec<SglStmt> },
 }
 
 impl AstNode for SglStmt {}
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum SglStmt {
     Do { act: Expr },
     Ass { dst: Expr, src: Expr },
 }
 
 impl AstNode for Expr {}
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum Expr {
     IdExpr {
         ident: String,
@@ -99,13 +104,13 @@ pub enum Expr {
     },
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum Uop {
     Neg,
     Not,
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum Binop {
     Add,
     Sub,
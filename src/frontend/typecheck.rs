@@ -6,8 +6,9 @@ use std::{
 
 use crate::meerast;
 use inline_colorization::*;
+use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub enum Type {
     Int,
     Bool,
@@ -516,7 +517,9 @@ pub fn check_decl(
             sigma_v.insert(name.clone(), t1);
             Ok(())
         }
-        meerast::Decl::DefDecl { name, val, is_pub } => {
+        meerast::Decl::DefDecl {
+            name, val, is_pub, ..
+        } => {
             let src_type = check_expr(sigma_v, sigma_m, gen_fresh_meta, gen_fresh_tyvar, val);
             let src_type = match src_type {
                 Ok(ty) => ty,
@@ -794,3 +797,42 @@ pub fn check_prog_test(srv: &meerast::Service) {
     println!("sigma_m:\n{:?}", sigma_m);
     println!("sigma_v:\n{:?}", sigma_v);
 }
+
+pub fn check_decls_then_action(
+    srv: &str,
+    decls: &[&meerast::Decl],
+    action: Option<&meerast::Expr>,
+) -> Result<HashMap<String, Type>, String> {
+    let mut gen_fresh_meta = FreshMetaGenerator::new(srv, 0);
+    let mut gen_fresh_tyvar = FreshTyvarGenerator::new(srv, 0);
+    let mut sigma_v: HashMap<String, Type> = HashMap::new();
+    let mut sigma_m: HashMap<String, Type> = HashMap::new();
+    let mut pub_access: HashMap<String, bool> = HashMap::new();
+    for decl in decls.iter() {
+        check_decl(
+            &mut sigma_v,
+            &mut sigma_m,
+            &mut pub_access,
+            &mut gen_fresh_meta,
+            &mut gen_fresh_tyvar,
+            decl,
+        )?;
+    }
+    if let Some(action) = action {
+        let action_type = check_expr(
+            &sigma_v,
+            &mut sigma_m,
+            &mut gen_fresh_meta,
+            &mut gen_fresh_tyvar,
+            action,
+        )?;
+        unify(
+            &mut sigma_m,
+            &mut gen_fresh_meta,
+            &mut gen_fresh_tyvar,
+            &action_type,
+            &Type::Action,
+        )?;
+    }
+    Ok(sigma_v)
+}
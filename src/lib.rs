@@ -0,0 +1,9 @@
+pub mod backend;
+pub mod frontend;
+pub mod runtime;
+
+/* The generated parser refers to the AST as `crate::meerast` */
+use frontend::meerast;
+
+pub use backend::message::Val;
+pub use runtime::Runtime;
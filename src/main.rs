@@ -1,43 +1,18 @@
-pub mod backend;
-pub mod frontend;
-
-use backend::srvmanager_proc::ServiceManager;
-use backend::srvmanager_proc::VarOrDef;
-use backend::worker::Worker;
-use frontend::meerast;
-use frontend::parse;
-use frontend::typecheck;
-use inline_colorization::*;
-use std::collections::{HashMap, HashSet};
-use std::io::Write;
-use std::sync::Arc;
-use std::{env, fs};
-use tokio::io;
-use tracing_subscriber::fmt::MakeWriter;
+use distr_intrp::backend::session::Role;
+use distr_intrp::backend::srvmanager_proc::ServiceManager;
+use distr_intrp::frontend::{meerast, parse};
+use distr_intrp::Runtime;
+use std::{env, fs, path::Path};
+use tokio::io::{AsyncBufReadExt, BufReader};
 
 #[tokio::main]
 async fn main() {
-    // let args: Vec<String> = env::args().collect();
-    // let file_name = &args[1];
-    // let src_prog = fs::read_to_string(file_name).expect("Unable to read file");
-    // // let ast = parse::ProgramParser::new()
-    // //     .parse(src_prog.as_str())
-    // //     .expect("Parsing fail");
-    // // let srvs = match ast {
-    // //     meerast::Program::Prog { services } => services,
-    // // };
-    // let ast = parse::ExprParser::new().parse(src_prog.as_str()).expect("");
-    // let val = Worker::compute_val(&ast, &HashMap::new());
-    // println!("{:?}", val);
-    // /* let (tx, rx) = mpsc::channel(100);
-    // let _ = tokio::spawn(srvmanager_proc::manager_proc(tx));
-    // let _ = tokio::spawn(defworker_proc::defworker_proc(rx)).await; */
     let file_appender = tracing_appender::rolling::RollingFileAppender::new(
         tracing_appender::rolling::Rotation::NEVER,
         "./",
         "log.txt",
     );
-    let (non_blocking, _guard) = tracing_appender::non_blocking(file_appender);
+    let (non_blocking, guard) = tracing_appender::non_blocking(file_appender);
     let subscriber = tracing_subscriber::fmt::Subscriber::builder()
         .with_max_level(tracing::Level::INFO)
         .with_writer(non_blocking)
@@ -54,131 +29,120 @@ async fn main() {
         .finish();
     tracing::subscriber::set_global_default(subscriber).expect("Unable to set global subscriber");
 
-    let mut svc_manager = ServiceManager::new();
+    /* `<program> <snapshot>` runs a program that is saved to `snapshot`,
+     * `--restore <snapshot>` picks it up again where it was saved */
+    let args: Vec<String> = env::args().collect();
+    match args.get(1).map(String::as_str) {
+        Some("--restore") => {
+            let path = args.get(2).expect("usage: --restore <snapshot>");
+            let rt = Runtime::restore(Path::new(path))
+                .await
+                .expect("restore fails");
+            run_stdin(rt).await;
+        }
+        Some(file_name) => {
+            let src_prog = fs::read_to_string(file_name).expect("Unable to read file");
+            let path = args.get(2).expect("usage: <program> <snapshot>");
+            let rt = Runtime::load_persisted(&src_prog, Path::new(path))
+                .await
+                .expect("load fails");
+            run_stdin(rt).await;
+        }
+        None => demo().await,
+    }
+    /* Flushes the log, after the workers have returned */
+    drop(guard);
+}
 
-    ServiceManager::create_worker(
-        "c",
-        VarOrDef::Def,
-        svc_manager.sender_to_manager.clone(),
-        &HashSet::new(),
-        &mut svc_manager.worker_inboxes,
-        &mut svc_manager.locks,
-        &mut svc_manager.typenv,
-        &mut svc_manager.var_or_def_env,
-        &mut svc_manager.dependgraph,
-    );
+/* Runs every line of stdin of the form `<service> <action>` and prints the
+ * value of those of the form `<service>.<name>`, then shuts down. */
+async fn run_stdin(rt: Runtime) {
+    let mut lines = BufReader::new(tokio::io::stdin()).lines();
+    while let Ok(Some(line)) = lines.next_line().await {
+        let line = line.trim();
+        if let Some((srv, action)) = line.split_once(' ') {
+            println!(
+                "{}",
+                match rt.run(srv, action).await {
+                    Ok(()) => String::from("ok"),
+                    Err(reason) => reason,
+                }
+            );
+        } else if let Some((srv, name)) = line.split_once('.') {
+            println!("{:?}", rt.read(srv, name).await);
+        }
+    }
+    rt.shutdown().await;
+}
 
-    ServiceManager::create_worker(
-        "a",
-        VarOrDef::Def,
-        svc_manager.sender_to_manager.clone(),
-        &HashSet::from_iter(vec!["c".to_string()].into_iter()),
-        &mut svc_manager.worker_inboxes,
-        &mut svc_manager.locks,
-        &mut svc_manager.typenv,
-        &mut svc_manager.var_or_def_env,
-        &mut svc_manager.dependgraph,
-    );
+async fn demo() {
+    let svc = ServiceManager::new().spawn();
+    let mut dev = svc
+        .open_session(Role::Developer)
+        .await
+        .expect("open session fails");
+    let user = svc
+        .open_session(Role::User)
+        .await
+        .expect("open session fails");
 
-    ServiceManager::create_worker(
-        "b",
-        VarOrDef::Def,
-        svc_manager.sender_to_manager.clone(),
-        &HashSet::from_iter(vec!["c".to_string()].into_iter()),
-        &mut svc_manager.worker_inboxes,
-        &mut svc_manager.locks,
-        &mut svc_manager.typenv,
-        &mut svc_manager.var_or_def_env,
-        &mut svc_manager.dependgraph,
-    );
+    for src in [
+        "var x = 1",
+        "def a = x + 1",
+        "def b = x * 2",
+        "pub def c = a + b",
+    ] {
+        let decl = parse::DeclParser::new().parse(src).unwrap();
+        dev.declare(decl).await.expect("declaration fails");
+    }
 
-    ServiceManager::create_worker(
-        "x",
-        VarOrDef::Var,
-        svc_manager.sender_to_manager.clone(),
-        &HashSet::from_iter(vec!["a".to_string(), "b".to_string()].into_iter()),
-        // &HashSet::new(),
-        &mut svc_manager.worker_inboxes,
-        &mut svc_manager.locks,
-        &mut svc_manager.typenv,
-        &mut svc_manager.var_or_def_env,
-        &mut svc_manager.dependgraph,
-    );
+    /* Record every value `c` takes while `x` is written repeatedly. */
+    let mut c_history = svc.subscribe("c").await.expect("subscribe fails");
+    for i in 2..=10 {
+        let action = parse::ExprParser::new()
+            .parse(&format!("action {{ x = {} }}", i))
+            .unwrap();
+        user.run_action(*action).await.expect("action fails");
+    }
 
-    let _ = ServiceManager::init_def_worker(
-        &mut svc_manager.worker_inboxes,
-        "c",
-        meerast::Expr::BopExpr {
-            opd1: Box::new(meerast::Expr::IdExpr {
-                ident: String::from("a"),
-            }),
-            opd2: Box::new(meerast::Expr::IdExpr {
-                ident: String::from("b"),
-            }),
-            bop: meerast::Binop::Add,
-        },
-    )
-    .await;
+    svc.await_quiescent().await;
 
-    let _ = ServiceManager::init_def_worker(
-        &mut svc_manager.worker_inboxes,
-        "a",
-        meerast::Expr::BopExpr {
-            opd1: Box::new(meerast::Expr::IdExpr {
-                ident: String::from("x"),
-            }),
-            opd2: Box::new(meerast::Expr::IntConst { val: 1 }),
-            bop: meerast::Binop::Add,
-        },
-    )
-    .await;
+    let (xval, aval, bval, cval) =
+        tokio::join!(svc.read("x"), svc.read("a"), svc.read("b"), svc.read("c"),);
 
-    let _ = ServiceManager::init_def_worker(
-        &mut svc_manager.worker_inboxes,
-        "b",
-        meerast::Expr::BopExpr {
-            opd1: Box::new(meerast::Expr::IdExpr {
-                ident: String::from("x"),
-            }),
-            opd2: Box::new(meerast::Expr::IntConst { val: 2 }),
-            bop: meerast::Binop::Mul,
-        },
-    )
-    .await;
+    println!("x: {:?}, a: {:?}, b: {:?}, c: {:?}", xval, aval, bval, cval);
 
-    let _ = ServiceManager::init_var_worker(
-        &mut svc_manager.worker_inboxes,
-        "x",
-        meerast::Expr::IntConst { val: 1 },
-    )
-    .await;
+    /* c = (x + 1) + (x * 2) = 3x + 1, so every value it takes is 1 modulo
+     * 3 unless a fresh `a` is mixed with a stale `b` */
+    while let Some((c, _)) = c_history.try_recv() {
+        println!("c became {:?}", c);
+    }
 
-    tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
+    /* A user action and a developer update submitted together run in either
+     * order; a stale action is re-typechecked before it may run. */
+    let action = parse::ExprParser::new().parse("action { x = a }").unwrap();
+    let update = parse::DeclParser::new().parse("def a = x * 3").unwrap();
+    let (action_res, update_res) = tokio::join!(user.run_action(*action), dev.update(update));
+    println!("action: {:?}, update a: {:?}", action_res, update_res);
 
-    let xval = ServiceManager::retrieve_val(
-        &svc_manager.worker_inboxes,
-        &mut svc_manager.receiver_from_workers,
-        "x",
-    )
-    .await;
-    let aval = ServiceManager::retrieve_val(
-        &svc_manager.worker_inboxes,
-        &mut svc_manager.receiver_from_workers,
-        "a",
-    )
-    .await;
-    let bval = ServiceManager::retrieve_val(
-        &svc_manager.worker_inboxes,
-        &mut svc_manager.receiver_from_workers,
-        "b",
-    )
-    .await;
-    let cval = ServiceManager::retrieve_val(
-        &svc_manager.worker_inboxes,
-        &mut svc_manager.receiver_from_workers,
-        "c",
-    )
-    .await;
+    /* Only developers may change the code, and they hear about updates of
+     * theirs that are aborted. */
+    let update = parse::DeclParser::new().parse("def b = x + 2").unwrap();
+    println!("user update b: {:?}", user.update(update).await);
+    let update = parse::DeclParser::new().parse("def b = x + true").unwrap();
+    println!("developer update b: {:?}", dev.update(update).await);
+    println!("developer notified: {:?}", dev.try_notification());
 
-    println!("x: {:?}, a: {:?}, b: {:?}, c: {:?}", xval, aval, bval, cval);
+    for cmd in ["delete a", "delete a cascade"] {
+        if let meerast::ReplInput::Delete { name, cascade } =
+            parse::ReplInputParser::new().parse(cmd).unwrap()
+        {
+            println!("{}: {:?}", cmd, dev.delete(&name, cascade).await);
+        }
+    }
+    println!("b after delete: {:?}", svc.read("b").await);
+    println!("c after delete: {:?}", svc.read("c").await);
+
+    svc.shutdown().await;
+    println!("after shutdown: {:?}", svc.read("x").await);
 }
@@ -0,0 +1,427 @@
+use crate::backend::lock::{self, Peer};
+use crate::backend::message::{Val, Version};
+use crate::backend::metrics::MetricsSnapshot;
+use crate::backend::pool::Pool;
+use crate::backend::session::{Role, Session};
+use crate::backend::snapshot::{ServiceSnapshot, Snapshot, Store};
+use crate::backend::srvmanager_proc::{ServiceHandle, ServiceManager, Subscription};
+use crate::backend::{dependency, remote};
+use crate::frontend::{meerast, parse};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::net::SocketAddr;
+use std::path::Path;
+use tokio::net::TcpListener;
+
+/// A loaded Meerkat program: one service manager per service, each running
+/// as its own task. `run` submits actions through a user session per service.
+pub struct Runtime {
+    services: HashMap<String, Session>,
+}
+
+impl Runtime {
+    /// Parses `program`, starts a manager for each of its services and
+    /// declares their vars and defs in dependency order.
+    pub async fn load(program: &str) -> Result<Runtime, String> {
+        Runtime::load_with_peers(program, &HashMap::new()).await
+    }
+
+    /// Like `load`, but services imported by `program` may also be hosted
+    /// by other processes, served at the addresses in `peers`.
+    pub async fn load_with_peers(
+        program: &str,
+        peers: &HashMap<String, SocketAddr>,
+    ) -> Result<Runtime, String> {
+        Runtime::load_with(program, peers, None, None, &BTreeMap::new()).await
+    }
+
+    /// Like `load`, but the workers of every service are run by `pool`
+    /// instead of each by a task of its own.
+    pub async fn load_pooled(program: &str, pool: Pool) -> Result<Runtime, String> {
+        Runtime::load_with(program, &HashMap::new(), Some(pool), None, &BTreeMap::new()).await
+    }
+
+    /// Like `load`, but every service is saved to the snapshot at `path`
+    /// after each update or action it commits, and on shutdown.
+    pub async fn load_persisted(program: &str, path: &Path) -> Result<Runtime, String> {
+        let store = Store::open(path, Snapshot::new(program));
+        Runtime::load_with(
+            program,
+            &HashMap::new(),
+            None,
+            Some(store),
+            &BTreeMap::new(),
+        )
+        .await
+    }
+
+    /// Loads the program saved at `path` again, each service with its
+    /// declarations as its code updates left them and its vars holding
+    /// their saved values instead of their declared ones. Goes on saving
+    /// there.
+    pub async fn restore(path: &Path) -> Result<Runtime, String> {
+        let snapshot = Snapshot::read(path).await?;
+        let program = snapshot.program.clone();
+        let saved = snapshot.services.clone();
+        let store = Store::open(path, snapshot);
+        Runtime::load_with(&program, &HashMap::new(), None, Some(store), &saved).await
+    }
+
+    async fn load_with(
+        program: &str,
+        peers: &HashMap<String, SocketAddr>,
+        pool: Option<Pool>,
+        store: Option<Store>,
+        saved: &BTreeMap<String, ServiceSnapshot>,
+    ) -> Result<Runtime, String> {
+        let ast = parse::ProgramParser::new()
+            .parse(program)
+            .map_err(|err| format!("parse error: {}", err))?;
+        let srvs = match ast {
+            meerast::Program::Prog { services } => services,
+        };
+        let mut pending: Vec<(String, Vec<meerast::Decl>)> = vec![];
+        for srv in srvs.into_iter() {
+            let (name, decls) = match srv {
+                meerast::Service::Srv { name, decls } => (name, decls),
+            };
+            if pending.iter().any(|(other, _)| *other == name) {
+                return Err(format!("service `{}` is declared twice", name));
+            }
+            pending.push((name, decls));
+        }
+
+        /* A service is loaded once everything it imports is available */
+        let mut rt = Runtime {
+            services: HashMap::new(),
+        };
+        while !pending.is_empty() {
+            let ready = pending.iter().position(|(_, decls)| {
+                decls.iter().all(|decl| match decl {
+                    meerast::Decl::Import { srv_name } => {
+                        rt.services.contains_key(srv_name) || peers.contains_key(srv_name)
+                    }
+                    _ => true,
+                })
+            });
+            let (name, decls) = match ready {
+                Some(idx) => pending.remove(idx),
+                None => {
+                    let names: Vec<&String> = pending.iter().map(|(name, _)| name).collect();
+                    return Err(format!(
+                        "services {:?} import unknown services or each other cyclically",
+                        names
+                    ));
+                }
+            };
+            let handle = rt
+                .load_service(
+                    &name,
+                    decls,
+                    peers,
+                    pool.clone(),
+                    store.clone(),
+                    saved.get(&name),
+                )
+                .await?;
+            let user = handle.open_session(Role::User).await?;
+            rt.services.insert(name, user);
+        }
+        Ok(rt)
+    }
+
+    async fn load_service(
+        &self,
+        srv: &str,
+        decls: Vec<meerast::Decl>,
+        peers: &HashMap<String, SocketAddr>,
+        pool: Option<Pool>,
+        store: Option<Store>,
+        saved: Option<&ServiceSnapshot>,
+    ) -> Result<ServiceHandle, String> {
+        let imports: HashSet<String> = decls
+            .iter()
+            .filter_map(|decl| match decl {
+                meerast::Decl::Import { srv_name } => Some(srv_name.clone()),
+                _ => None,
+            })
+            .collect();
+        let mut members: BTreeSet<(String, String)> = BTreeSet::new();
+        let mut decl_map: HashMap<String, meerast::Decl> = HashMap::new();
+        for mut decl in decls.into_iter() {
+            let name = match &mut decl {
+                meerast::Decl::Import { srv_name: _ } => continue,
+                meerast::Decl::VarDecl { name, val } | meerast::Decl::DefDecl { name, val, .. } => {
+                    resolve_members(val, Some(&imports), &mut members)?;
+                    name.clone()
+                }
+            };
+            if decl_map.contains_key(&name) {
+                return Err(format!("`{}` is declared twice in `{}`", name, srv));
+            }
+            decl_map.insert(name, decl);
+        }
+        /* A restored service gets the declarations it had saved, which have
+         * their members resolved already, and its vars their saved values */
+        if let Some(saved) = saved {
+            decl_map.clear();
+            for (name, (decl, _)) in saved.decls.iter() {
+                let decl = match (decl, saved.vars.get(name)) {
+                    (meerast::Decl::VarDecl { .. }, Some((val, _))) => meerast::Decl::VarDecl {
+                        name: name.clone(),
+                        val: literal(val.clone()),
+                    },
+                    _ => decl.clone(),
+                };
+                decl_map.insert(name.clone(), decl);
+            }
+        }
+
+        /* Every imported member is mirrored by a local var, kept up to date
+         * by a subscription to the service that hosts it. The host locks the
+         * mirror whenever it writes the member. */
+        let manager = match pool {
+            Some(pool) => ServiceManager::pooled(pool),
+            None => ServiceManager::new(),
+        };
+        let handle = manager.spawn();
+        /* Its vars and defs carry on from the versions they were saved with */
+        if let Some(saved) = saved {
+            let mut versions: HashMap<String, Version> = saved
+                .decls
+                .iter()
+                .map(|(name, (_, code))| {
+                    let version = Version {
+                        code: *code,
+                        value: 0,
+                    };
+                    (name.clone(), version)
+                })
+                .collect();
+            for (name, (_, version)) in saved.vars.iter() {
+                versions.insert(name.clone(), *version);
+            }
+            handle.seed_versions(versions).await?;
+        }
+        let mut mirrors: Vec<(String, Peer, String, Subscription)> = vec![];
+        for (srv_name, member) in members.into_iter() {
+            let mirror = mirror_name(&srv_name, &member);
+            let locker = lock::forward_locks(handle.clone(), mirror.clone());
+            let (peer, mut subscription) = match self.services.get(&srv_name) {
+                Some(host) => {
+                    let host = host.handle().clone();
+                    let subscription = host.subscribe_mirror(&member, locker).await?;
+                    (Peer::Local(host), subscription)
+                }
+                None => {
+                    let addr = peers[&srv_name];
+                    let subscription = remote::subscribe_mirror(addr, &member, locker).await?;
+                    (Peer::Remote(addr), subscription)
+                }
+            };
+            let (first_val, _) = subscription
+                .recv()
+                .await
+                .ok_or_else(|| format!("`{}` went away", mirror))?;
+            let decl = meerast::Decl::VarDecl {
+                name: mirror.clone(),
+                val: mirror_literal(&mirror, first_val)?,
+            };
+            decl_map.insert(mirror.clone(), decl);
+            mirrors.push((mirror, peer, member, subscription));
+        }
+
+        let mut dependency_graph: HashMap<String, HashSet<String>> = HashMap::new();
+        for (name, decl) in decl_map.iter() {
+            dependency_graph.insert(name.clone(), HashSet::new());
+            dependency::decl_dependency(&mut dependency_graph, decl);
+        }
+        dependency::check_cyclic(&dependency_graph)
+            .map_err(|err_msg| format!("{} in `{}`", err_msg, srv))?;
+
+        let developer = handle.open_session(Role::Developer).await?;
+        for name in dependency::topo_order(&dependency_graph).into_iter() {
+            let decl = decl_map.remove(&name).unwrap();
+            developer
+                .declare(decl)
+                .await
+                .map_err(|err_msg| format!("{}: {}", srv, err_msg))?;
+        }
+        for (mirror, peer, member, subscription) in mirrors.into_iter() {
+            handle.register_mirror(&mirror, peer, &member).await?;
+            let feeder = handle.open_session(Role::User).await?;
+            tokio::spawn(feed_mirror(mirror, subscription, feeder));
+        }
+        handle.await_quiescent().await;
+        /* Saving only starts once everything is declared */
+        if let Some(store) = store {
+            handle.persist(store, srv).await?;
+        }
+        Ok(handle)
+    }
+
+    /// Serves the public defs of `srv` to other processes over `listener`.
+    pub fn serve(&self, srv: &str, listener: TcpListener) -> Result<(), String> {
+        let handle = self.service(srv)?.clone();
+        tokio::spawn(remote::serve(handle, listener));
+        Ok(())
+    }
+
+    pub fn service(&self, srv: &str) -> Result<&ServiceHandle, String> {
+        self.services
+            .get(srv)
+            .map(|user| user.handle())
+            .ok_or_else(|| format!("service `{}` is not loaded", srv))
+    }
+
+    pub async fn open_session(&self, srv: &str, role: Role) -> Result<Session, String> {
+        self.service(srv)?.open_session(role).await
+    }
+
+    /// Current value of `name` in `srv`, `None` while it is not computed yet.
+    pub async fn read(&self, srv: &str, name: &str) -> Result<Option<Val>, String> {
+        let (val, _) = self.service(srv)?.read(name).await?;
+        Ok(val)
+    }
+
+    pub async fn metrics(&self, srv: &str) -> Result<MetricsSnapshot, String> {
+        self.service(srv)?.metrics().await
+    }
+
+    /// Subscribes to the public def `name` in `srv`.
+    pub async fn subscribe(&self, srv: &str, name: &str) -> Result<Subscription, String> {
+        self.service(srv)?.subscribe(name).await
+    }
+
+    /// Parses `action`, an expression of action type such as
+    /// `action { x = 1 }` or `add(2)`, and runs it in `srv`. Returns once its
+    /// writes have propagated.
+    pub async fn run(&self, srv: &str, action: &str) -> Result<(), String> {
+        let user = self
+            .services
+            .get(srv)
+            .ok_or_else(|| format!("service `{}` is not loaded", srv))?;
+        let mut action = parse::ExprParser::new()
+            .parse(action)
+            .map_err(|err| format!("parse error: {}", err))?;
+        resolve_members(&mut action, None, &mut BTreeSet::new())?;
+        user.run_action(*action).await?;
+        user.handle().await_quiescent().await;
+        Ok(())
+    }
+
+    /// Shuts every service down, each as `ServiceHandle::shutdown` does.
+    pub async fn shutdown(&self) {
+        for user in self.services.values() {
+            user.handle().shutdown().await;
+        }
+    }
+}
+
+fn mirror_name(srv_name: &str, member: &str) -> String {
+    /* Not a valid identifier, so it never clashes with a local name */
+    format!("{}.{}", srv_name, member)
+}
+
+/* The expression a var is restored from, which evaluates to `val` */
+fn literal(val: Val) -> meerast::Expr {
+    match val {
+        Val::Int(val) => meerast::Expr::IntConst { val },
+        Val::Bool(val) => meerast::Expr::BoolConst { val },
+        Val::Action(expr) | Val::Lambda(expr) => expr,
+    }
+}
+
+fn mirror_literal(mirror: &str, val: Val) -> Result<meerast::Expr, String> {
+    match val {
+        Val::Int(val) => Ok(meerast::Expr::IntConst { val }),
+        Val::Bool(val) => Ok(meerast::Expr::BoolConst { val }),
+        _ => Err(format!(
+            "cannot import `{}`: only int and bool members can be imported yet",
+            mirror
+        )),
+    }
+}
+
+async fn feed_mirror(mirror: String, mut subscription: Subscription, feeder: Session) {
+    while let Some((val, _)) = subscription.recv().await {
+        let src = match mirror_literal(&mirror, val) {
+            Ok(src) => src,
+            Err(_) => break,
+        };
+        let action = meerast::Expr::Action {
+            stmt: meerast::Stmt::Stmt {
+                sgl_stmts: vec![meerast::SglStmt::Ass {
+                    dst: meerast::Expr::IdExpr {
+                        ident: mirror.clone(),
+                    },
+                    src,
+                }],
+            },
+        };
+        if feeder.run_action(action).await.is_err() {
+            break;
+        }
+    }
+}
+
+/* Replaces every `srv.member` in `expr` by the name of its mirror and
+ * records it in `members`. If `imports` is given, `srv` must be one of
+ * them. */
+fn resolve_members(
+    expr: &mut meerast::Expr,
+    imports: Option<&HashSet<String>>,
+    members: &mut BTreeSet<(String, String)>,
+) -> Result<(), String> {
+    match expr {
+        meerast::Expr::IdExpr { ident: _ }
+        | meerast::Expr::IntConst { val: _ }
+        | meerast::Expr::BoolConst { val: _ } => Ok(()),
+        meerast::Expr::Action { stmt } => {
+            let sgls = match stmt {
+                meerast::Stmt::Stmt { sgl_stmts } => sgl_stmts,
+            };
+            for sgl in sgls.iter_mut() {
+                match sgl {
+                    meerast::SglStmt::Do { act } => resolve_members(act, imports, members)?,
+                    meerast::SglStmt::Ass { dst, src } => {
+                        resolve_members(dst, imports, members)?;
+                        resolve_members(src, imports, members)?;
+                    }
+                }
+            }
+            Ok(())
+        }
+        meerast::Expr::Member { srv_name, member } => {
+            let member_name = match member.as_ref() {
+                meerast::Expr::IdExpr { ident } => ident.clone(),
+                _ => return Err(format!("malformed member of `{}`", srv_name)),
+            };
+            if imports.is_some_and(|imports| !imports.contains(srv_name)) {
+                return Err(format!("`{}` is not imported", srv_name));
+            }
+            members.insert((srv_name.clone(), member_name.clone()));
+            *expr = meerast::Expr::IdExpr {
+                ident: mirror_name(srv_name, &member_name),
+            };
+            Ok(())
+        }
+        meerast::Expr::Apply { fun, args } => {
+            resolve_members(fun, imports, members)?;
+            for arg in args.iter_mut() {
+                resolve_members(arg, imports, members)?;
+            }
+            Ok(())
+        }
+        meerast::Expr::BopExpr { opd1, opd2, bop: _ } => {
+            resolve_members(opd1, imports, members)?;
+            resolve_members(opd2, imports, members)
+        }
+        meerast::Expr::UopExpr { opd, uop: _ } => resolve_members(opd, imports, members),
+        meerast::Expr::IfExpr { cond, then, elze } => {
+            resolve_members(cond, imports, members)?;
+            resolve_members(then, imports, members)?;
+            resolve_members(elze, imports, members)
+        }
+        meerast::Expr::Lambda { pars: _, body } => resolve_members(body, imports, members),
+    }
+}
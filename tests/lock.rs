@@ -0,0 +1,191 @@
+use distr_intrp::backend::lock::{Acquire, LockTable, LockType, TxnClock, TxnId};
+use distr_intrp::backend::remote::LockClient;
+use distr_intrp::backend::session::Role;
+use distr_intrp::frontend::parse;
+use distr_intrp::{Runtime, Val};
+use std::collections::HashMap;
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio::sync::oneshot;
+
+const COUNTER: &str = "
+service counter {
+    var _count = 1
+    pub def count = _count
+    pub def add = fn n => action { _count = _count + n }
+}
+";
+
+const UI: &str = "
+service ui {
+    import counter
+    var offset = 10
+    pub def display = counter.count + offset
+}
+";
+
+fn txn(timestamp: u64) -> TxnId {
+    TxnId {
+        timestamp,
+        origin: 0,
+    }
+}
+
+#[test]
+fn older_transactions_wait_and_younger_ones_die() {
+    let mut table = LockTable::new();
+    assert_eq!(
+        table.acquire(txn(2), "x", LockType::RLock),
+        Acquire::Granted
+    );
+    assert_eq!(
+        table.acquire(txn(3), "x", LockType::RLock),
+        Acquire::Granted
+    );
+    assert_eq!(table.acquire(txn(1), "x", LockType::WLock), Acquire::Wait);
+    assert_eq!(table.acquire(txn(4), "x", LockType::WLock), Acquire::Die);
+    /* A sole reader may upgrade */
+    assert_eq!(
+        table.acquire(txn(5), "y", LockType::RLock),
+        Acquire::Granted
+    );
+    assert_eq!(
+        table.acquire(txn(5), "y", LockType::WLock),
+        Acquire::Granted
+    );
+    assert_eq!(table.acquire(txn(6), "y", LockType::RLock), Acquire::Die);
+}
+
+#[test]
+fn releasing_grants_waiters() {
+    let mut table = LockTable::new();
+    assert_eq!(
+        table.acquire(txn(2), "x", LockType::WLock),
+        Acquire::Granted
+    );
+    let (reply_to, mut reply) = oneshot::channel();
+    table.request(txn(1), "x", LockType::WLock, reply_to);
+    assert!(reply.try_recv().is_err());
+    table.release_all(txn(2));
+    assert_eq!(reply.try_recv(), Ok(Ok(())));
+    assert_eq!(table.acquire(txn(3), "x", LockType::RLock), Acquire::Die);
+    table.release_all(txn(1));
+    assert_eq!(
+        table.acquire(txn(3), "x", LockType::RLock),
+        Acquire::Granted
+    );
+}
+
+#[test]
+fn transaction_ids_always_increase() {
+    let mut clock = TxnClock::new(0);
+    let mut last = clock.stamp();
+    /* Many fall within the same microsecond */
+    for _ in 0..10_000 {
+        let next = clock.stamp();
+        assert!(last < next);
+        last = next;
+    }
+}
+
+#[tokio::test]
+async fn locks_are_held_until_defs_downstream_recompute() {
+    let mut program = String::from("service s { var x = 0 var z = 0 def d0 = x + 1");
+    for i in 1..300 {
+        program += &format!(" def d{} = d{} + 1", i, i - 1);
+    }
+    program += " }";
+    let rt = Runtime::load(&program).await.unwrap();
+    let user = rt.open_session("s", Role::User).await.unwrap();
+    let action = |src: &str| *parse::ExprParser::new().parse(src).unwrap();
+    /* Without waiting for quiescence in between, the second action still
+     * reads what the first one wrote */
+    for i in 1..=10 {
+        user.run_action(action(&format!("action {{ x = {} }}", i)))
+            .await
+            .unwrap();
+        user.run_action(action("action { z = d299 }"))
+            .await
+            .unwrap();
+        assert_eq!(rt.read("s", "z").await, Ok(Some(Val::Int(i + 300))));
+    }
+}
+
+#[tokio::test]
+async fn remote_locks_hold_back_local_writes() {
+    let counter = Runtime::load(COUNTER).await.unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    counter.serve("counter", listener).unwrap();
+
+    /* Older than anything the counter starts from now on */
+    let holder = txn(1);
+    let mut client = LockClient::connect(addr).await.unwrap();
+    client.lock(holder, "count", LockType::RLock).await.unwrap();
+    let mut younger = LockClient::connect(addr).await.unwrap();
+    assert!(younger
+        .lock(txn(2), "count", LockType::WLock)
+        .await
+        .is_err());
+
+    let adding = tokio::spawn(async move {
+        counter.run("counter", "add(2)").await.unwrap();
+        counter
+    });
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert!(!adding.is_finished());
+    client.release(holder).await;
+    let counter = tokio::time::timeout(Duration::from_secs(5), adding)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(
+        counter.read("counter", "count").await,
+        Ok(Some(Val::Int(3)))
+    );
+}
+
+#[tokio::test]
+async fn writes_lock_remote_mirrors() {
+    let counter = Runtime::load(COUNTER).await.unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    counter.serve("counter", listener).unwrap();
+    let peers = HashMap::from([(String::from("counter"), addr)]);
+    let ui = Runtime::load_with_peers(UI, &peers).await.unwrap();
+
+    /* While the mirror is locked in `ui`, `counter` cannot write `count` */
+    let holder = txn(1);
+    let ui_handle = ui.service("ui").unwrap().clone();
+    ui_handle
+        .lock(holder, "counter.count", LockType::RLock)
+        .await
+        .unwrap();
+    let adding = tokio::spawn(async move {
+        counter.run("counter", "add(5)").await.unwrap();
+        counter
+    });
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert!(!adding.is_finished());
+    ui_handle.release(holder).await;
+    let counter = tokio::time::timeout(Duration::from_secs(5), adding)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(
+        counter.read("counter", "count").await,
+        Ok(Some(Val::Int(6)))
+    );
+
+    let mut display = ui.subscribe("ui", "display").await.unwrap();
+    let settled = tokio::time::timeout(Duration::from_secs(5), async {
+        while let Some((val, _)) = display.recv().await {
+            if val == Val::Int(16) {
+                return true;
+            }
+        }
+        false
+    })
+    .await;
+    assert_eq!(settled, Ok(true));
+}
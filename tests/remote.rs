@@ -0,0 +1,101 @@
+use distr_intrp::backend::remote;
+use distr_intrp::backend::wire::{Encoding, WireMessage};
+use distr_intrp::{Runtime, Val};
+use std::collections::HashMap;
+use std::time::Duration;
+use tokio::net::TcpListener;
+
+const COUNTER: &str = "
+service counter {
+    var _count = 1
+    pub def count = _count
+    pub def add = fn n => action { _count = _count + n }
+}
+";
+
+const UI: &str = "
+service ui {
+    import counter
+    var offset = 10
+    pub def display = counter.count + offset
+}
+";
+
+async fn host_counter() -> (Runtime, std::net::SocketAddr) {
+    let counter = Runtime::load(COUNTER).await.unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    counter.serve("counter", listener).unwrap();
+    (counter, addr)
+}
+
+#[tokio::test]
+async fn frames_round_trip() {
+    let msg = WireMessage::PredUpdatedTo {
+        pred_name: String::from("count"),
+        pred_value: Val::Int(-3),
+        pred_version: Default::default(),
+    };
+    let mut buf: Vec<u8> = vec![];
+    remote::write_frame(&mut buf, &msg, Encoding::Json)
+        .await
+        .unwrap();
+    remote::write_frame(&mut buf, &msg, Encoding::Binary)
+        .await
+        .unwrap();
+    let mut reader = &buf[..];
+    assert_eq!(
+        remote::read_frame(&mut reader).await,
+        Ok(Some((msg.clone(), Encoding::Json)))
+    );
+    assert_eq!(
+        remote::read_frame(&mut reader).await,
+        Ok(Some((msg, Encoding::Binary)))
+    );
+    assert_eq!(remote::read_frame(&mut reader).await, Ok(None));
+}
+
+#[tokio::test]
+async fn remote_subscribers_follow_the_host() {
+    let (counter, addr) = host_counter().await;
+    assert!(remote::subscribe(addr, "_count").await.is_err());
+
+    let mut count = remote::subscribe(addr, "count").await.unwrap();
+    let mut count_json = remote::subscribe_with(addr, "count", Encoding::Json)
+        .await
+        .unwrap();
+    assert_eq!(count.recv().await.map(|(v, _)| v), Some(Val::Int(1)));
+    assert_eq!(count_json.recv().await.map(|(v, _)| v), Some(Val::Int(1)));
+    counter.run("counter", "add(2)").await.unwrap();
+    assert_eq!(count.recv().await.map(|(v, _)| v), Some(Val::Int(3)));
+    assert_eq!(count_json.recv().await.map(|(v, _)| v), Some(Val::Int(3)));
+}
+
+#[tokio::test]
+async fn imports_resolve_to_a_remote_host() {
+    let (counter, addr) = host_counter().await;
+    let peers = HashMap::from([(String::from("counter"), addr)]);
+    let ui = Runtime::load_with_peers(UI, &peers).await.unwrap();
+    assert_eq!(ui.read("ui", "display").await, Ok(Some(Val::Int(11))));
+
+    let mut display = ui.subscribe("ui", "display").await.unwrap();
+    assert_eq!(display.recv().await.map(|(v, _)| v), Some(Val::Int(11)));
+    counter.run("counter", "add(5)").await.unwrap();
+    let next = tokio::time::timeout(Duration::from_secs(5), display.recv())
+        .await
+        .unwrap();
+    assert_eq!(next.map(|(v, _)| v), Some(Val::Int(16)));
+}
+
+#[tokio::test]
+async fn imports_resolve_within_one_program() {
+    let program = format!("{}{}", UI, COUNTER);
+    let rt = Runtime::load(&program).await.unwrap();
+    assert_eq!(rt.read("ui", "display").await, Ok(Some(Val::Int(11))));
+    assert!(Runtime::load(UI).await.is_err());
+    assert!(Runtime::load(
+        "service s { import t def a = t.b } service t { import s pub def b = 1 }"
+    )
+    .await
+    .is_err());
+}
@@ -0,0 +1,511 @@
+use distr_intrp::backend::pool::Pool;
+use distr_intrp::backend::session::{Notification, Role};
+use distr_intrp::backend::srvmanager_proc::ServiceManager;
+use distr_intrp::frontend::meerast::ReplInput;
+use distr_intrp::frontend::parse;
+use distr_intrp::{Runtime, Val};
+
+const COUNTER: &str = "
+service counter {
+    var _count = 1
+    pub def count = _count
+    pub def add = fn n => action { _count = _count + n }
+    pub def id = fn x => x
+    pub def a = id(1145)
+}
+";
+
+#[tokio::test]
+async fn load_computes_initial_values() {
+    let rt = Runtime::load(COUNTER).await.unwrap();
+    assert_eq!(rt.read("counter", "count").await, Ok(Some(Val::Int(1))));
+    assert_eq!(rt.read("counter", "a").await, Ok(Some(Val::Int(1145))));
+}
+
+#[tokio::test]
+async fn load_orders_declarations_by_dependency() {
+    let rt = Runtime::load("service s { def b = a * 2 def a = x + 1 var x = 1 }")
+        .await
+        .unwrap();
+    assert_eq!(rt.read("s", "b").await, Ok(Some(Val::Int(4))));
+}
+
+/* Workers run in parallel with the manager here, so a def can be given a new
+ * successor while it is still behind on an earlier round. */
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn load_settles_while_workers_run_in_parallel() {
+    for _ in 0..20 {
+        let rt = tokio::time::timeout(
+            std::time::Duration::from_secs(5),
+            Runtime::load("service s { var x = 0 def a = x + 1 def b = a + x }"),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+        assert_eq!(rt.read("s", "b").await, Ok(Some(Val::Int(1))));
+    }
+}
+
+#[tokio::test]
+async fn load_rejects_ill_typed_programs() {
+    assert!(Runtime::load("service s { var x = 1 def y = x + true }")
+        .await
+        .is_err());
+    assert!(Runtime::load("service s { def y = z }").await.is_err());
+    assert!(Runtime::load("service s { def a = b def b = a }")
+        .await
+        .is_err());
+}
+
+#[tokio::test]
+async fn run_propagates_writes() {
+    let rt = Runtime::load(COUNTER).await.unwrap();
+    rt.run("counter", "add(2)").await.unwrap();
+    rt.run("counter", "action { do add(3); do add(4) }")
+        .await
+        .unwrap();
+    /* Reads within one action see the state from before it */
+    assert_eq!(rt.read("counter", "count").await, Ok(Some(Val::Int(7))));
+}
+
+#[tokio::test]
+async fn run_rejects_bad_actions() {
+    let rt = Runtime::load(COUNTER).await.unwrap();
+    assert!(rt.run("counter", "action { count = 2 }").await.is_err());
+    assert!(rt.run("counter", "action { _count = true }").await.is_err());
+    assert!(rt.run("nowhere", "add(1)").await.is_err());
+}
+
+#[tokio::test]
+async fn propagation_is_glitch_free() {
+    let rt = Runtime::load("service s { var x = 1 def a = x + 1 def b = x * 2 pub def c = a + b }")
+        .await
+        .unwrap();
+    let mut c_history = rt.subscribe("s", "c").await.unwrap();
+    for i in 2..=10 {
+        rt.run("s", &format!("action {{ x = {} }}", i))
+            .await
+            .unwrap();
+    }
+    assert_eq!(rt.read("s", "c").await, Ok(Some(Val::Int(31))));
+
+    /* c = 3x + 1, so mixing a fresh `a` with a stale `b` would show up as a
+     * value that is not 1 modulo 3. */
+    let mut seen = vec![];
+    while let Some((c, _)) = c_history.try_recv() {
+        seen.push(c);
+    }
+    assert_eq!(
+        seen,
+        (1..=10).map(|x| Val::Int(3 * x + 1)).collect::<Vec<_>>()
+    );
+}
+
+#[tokio::test]
+async fn pooled_workers_propagate_like_tasks() {
+    let rt = Runtime::load_pooled(
+        "service s { var x = 1 def a = x + 1 def b = x * 2 pub def c = a + b }",
+        Pool::new(2),
+    )
+    .await
+    .unwrap();
+    let mut c_history = rt.subscribe("s", "c").await.unwrap();
+    for i in 2..=10 {
+        rt.run("s", &format!("action {{ x = {} }}", i))
+            .await
+            .unwrap();
+    }
+    assert_eq!(rt.read("s", "c").await, Ok(Some(Val::Int(31))));
+    let mut seen = vec![];
+    while let Some((c, _)) = c_history.try_recv() {
+        seen.push(c);
+    }
+    assert_eq!(
+        seen,
+        (1..=10).map(|x| Val::Int(3 * x + 1)).collect::<Vec<_>>()
+    );
+
+    /* Deleted workers are retired from their shard */
+    let dev = rt.open_session("s", Role::Developer).await.unwrap();
+    dev.delete("a", true).await.unwrap();
+    rt.run("s", "action { x = 11 }").await.unwrap();
+    rt.service("s").unwrap().await_quiescent().await;
+    assert_eq!(rt.read("s", "b").await, Ok(Some(Val::Int(22))));
+    assert!(rt.read("s", "c").await.is_err());
+}
+
+#[tokio::test]
+async fn propagation_stops_at_unchanged_values() {
+    let rt = Runtime::load(
+        "service s { var x = 1 def a = x > 0 def b = x * 2 pub def c = if a then 1 else 0 pub def d = b + 1 }",
+    )
+    .await
+    .unwrap();
+    let (_, c_version) = rt.service("s").unwrap().read("c").await.unwrap();
+    let before = rt.metrics("s").await.unwrap();
+    rt.run("s", "action { x = 2 }").await.unwrap();
+    /* Changes travel the whole chain x -> b -> d */
+    assert_eq!(rt.read("s", "d").await, Ok(Some(Val::Int(5))));
+    /* but stop at `a`, whose value stays the same */
+    assert_eq!(
+        rt.service("s").unwrap().read("c").await,
+        Ok((Some(Val::Int(1)), c_version))
+    );
+    /* `a`, `b` and `d` recompute, `c` does not */
+    let after = rt.metrics("s").await.unwrap();
+    assert_eq!(after.recomputes - before.recomputes, 3);
+    assert_eq!(after.unchanged - before.unchanged, 1);
+}
+
+#[tokio::test]
+async fn lazy_defs_compute_only_while_observed() {
+    let rt = Runtime::load("lazy service s { var x = 1 pub def double = x * 2 }")
+        .await
+        .unwrap();
+    let s = rt.service("s").unwrap().clone();
+    let recomputes = || async { rt.metrics("s").await.unwrap().recomputes };
+    rt.run("s", "action { x = 2 }").await.unwrap();
+    s.await_quiescent().await;
+    assert_eq!(recomputes().await, 0);
+
+    /* A read wakes `double` once, after which it is dormant again */
+    assert_eq!(rt.read("s", "double").await, Ok(Some(Val::Int(4))));
+    assert_eq!(recomputes().await, 1);
+    rt.run("s", "action { x = 3 }").await.unwrap();
+    s.await_quiescent().await;
+    assert_eq!(recomputes().await, 1);
+
+    /* A subscription keeps it live until dropped */
+    let mut double = rt.subscribe("s", "double").await.unwrap();
+    assert_eq!(double.recv().await.map(|(val, _)| val), Some(Val::Int(6)));
+    rt.run("s", "action { x = 4 }").await.unwrap();
+    assert_eq!(double.recv().await.map(|(val, _)| val), Some(Val::Int(8)));
+    drop(double);
+    let live = recomputes().await;
+    for i in 5..=6 {
+        rt.run("s", &format!("action {{ x = {} }}", i))
+            .await
+            .unwrap();
+    }
+    s.await_quiescent().await;
+    assert_eq!(recomputes().await, live);
+    assert_eq!(rt.read("s", "double").await, Ok(Some(Val::Int(12))));
+}
+
+#[tokio::test]
+async fn lazy_is_only_a_keyword_before_service_and_def() {
+    let rt = Runtime::load("service s { var lazy = 1 lazy def a = lazy + 1 pub lazy def b = a }")
+        .await
+        .unwrap();
+    let s = rt.service("s").unwrap().clone();
+    rt.run("s", "action { lazy = 2 }").await.unwrap();
+    s.await_quiescent().await;
+    assert_eq!(rt.metrics("s").await.unwrap().recomputes, 0);
+    assert_eq!(rt.read("s", "b").await, Ok(Some(Val::Int(3))));
+    assert!(parse::ReplInputParser::new()
+        .parse("eager def c = 1")
+        .is_err());
+}
+
+#[tokio::test]
+async fn unobserved_lazy_chains_stay_dormant() {
+    let rt = Runtime::load("lazy service s { var x = 0 def a = x + 1 pub def b = a + 1 }")
+        .await
+        .unwrap();
+    let s = rt.service("s").unwrap().clone();
+    let before = rt.metrics("s").await.unwrap();
+    for i in 1..=10 {
+        rt.run("s", &format!("action {{ x = {} }}", i))
+            .await
+            .unwrap();
+    }
+    s.await_quiescent().await;
+    /* `a` is only read by `b`, which is dormant */
+    assert_eq!(rt.metrics("s").await.unwrap().recomputes, before.recomputes);
+
+    /* Reading `b` wakes `a` as well */
+    assert_eq!(rt.read("s", "b").await, Ok(Some(Val::Int(12))));
+
+    /* Reads of a def that is live already do not wake it again */
+    let mut b = rt.subscribe("s", "b").await.unwrap();
+    assert_eq!(b.recv().await.map(|(val, _)| val), Some(Val::Int(12)));
+    let live = rt.metrics("s").await.unwrap();
+    for _ in 0..3 {
+        assert_eq!(rt.read("s", "b").await, Ok(Some(Val::Int(12))));
+    }
+    assert_eq!(rt.metrics("s").await.unwrap(), live);
+}
+
+#[tokio::test]
+async fn subscriptions_report_changes_only() {
+    let rt = Runtime::load("service s { var x = 1 pub def big = x > 5 def small = x < 5 }")
+        .await
+        .unwrap();
+    assert!(rt.subscribe("s", "x").await.is_err());
+    assert!(rt.subscribe("s", "small").await.is_err());
+
+    let mut big = rt.subscribe("s", "big").await.unwrap();
+    let dropped = rt.subscribe("s", "big").await.unwrap();
+    drop(dropped);
+    for i in 2..=10 {
+        rt.run("s", &format!("action {{ x = {} }}", i))
+            .await
+            .unwrap();
+    }
+    let (first, first_version) = big.try_recv().unwrap();
+    let (second, second_version) = big.try_recv().unwrap();
+    assert_eq!((first, second), (Val::Bool(false), Val::Bool(true)));
+    assert!(first_version.value < second_version.value);
+    assert_eq!(big.try_recv(), None);
+}
+
+/* More updates than a subscription buffers: the ones in between may be
+ * missed, but not the last one */
+#[tokio::test]
+async fn subscribers_that_fall_behind_get_the_latest_value() {
+    let rt = Runtime::load("service s { var x = 0 pub def y = x }")
+        .await
+        .unwrap();
+    let mut y = rt.subscribe("s", "y").await.unwrap();
+    for i in 1..=1100 {
+        rt.run("s", &format!("action {{ x = {} }}", i))
+            .await
+            .unwrap();
+    }
+    assert_eq!(rt.read("s", "y").await, Ok(Some(Val::Int(1100))));
+    let mut last = None;
+    while last != Some(Val::Int(1100)) {
+        let next = tokio::time::timeout(std::time::Duration::from_secs(5), y.recv());
+        last = next.await.unwrap().map(|(val, _)| val);
+    }
+}
+
+#[tokio::test]
+async fn only_developers_change_code() {
+    let rt = Runtime::load(COUNTER).await.unwrap();
+    let user = rt.open_session("counter", Role::User).await.unwrap();
+    let mut dev = rt.open_session("counter", Role::Developer).await.unwrap();
+    let decl = |src: &str| parse::DeclParser::new().parse(src).unwrap();
+
+    assert!(user.update(decl("def count = _count + 1")).await.is_err());
+    assert!(user.delete("a", false).await.is_err());
+    assert!(user.declare(decl("def b = 1")).await.is_err());
+    user.run_action(*parse::ExprParser::new().parse("add(1)").unwrap())
+        .await
+        .unwrap();
+
+    dev.update(decl("def count = _count + 1")).await.unwrap();
+    rt.service("counter").unwrap().await_quiescent().await;
+    assert_eq!(rt.read("counter", "count").await, Ok(Some(Val::Int(3))));
+    assert_eq!(dev.try_notification(), None);
+
+    assert!(dev.update(decl("def count = _count + true")).await.is_err());
+    assert!(matches!(
+        dev.try_notification(),
+        Some(Notification::UpdateAborted { name, .. }) if name == "count"
+    ));
+}
+
+#[tokio::test]
+async fn deletes_refuse_names_still_in_use() {
+    let rt = Runtime::load("service s { var cascade = 1 def p = cascade + 1 def q = p }")
+        .await
+        .unwrap();
+    let dev = rt.open_session("s", Role::Developer).await.unwrap();
+    assert_eq!(
+        dev.delete("p", false).await,
+        Err(String::from("`p` is still used by q"))
+    );
+    assert_eq!(rt.read("s", "q").await, Ok(Some(Val::Int(2))));
+
+    /* `cascade` is only a keyword after the name to delete */
+    let cmd = parse::ReplInputParser::new()
+        .parse("delete p cascade")
+        .unwrap();
+    let ReplInput::Delete { name, cascade } = cmd else {
+        panic!("`{:?}` is not a delete", cmd);
+    };
+    dev.delete(&name, cascade).await.unwrap();
+    assert!(rt.read("s", "q").await.is_err());
+    assert!(parse::ReplInputParser::new().parse("delete p q").is_err());
+}
+
+#[tokio::test]
+async fn deletes_wait_for_rounds_in_flight() {
+    let rt = Runtime::load("service s { var x = 0 var y = 0 def p = x + y def q = p }")
+        .await
+        .unwrap();
+    let user = rt.open_session("s", Role::User).await.unwrap();
+    let dev = rt.open_session("s", Role::Developer).await.unwrap();
+    let decl = |src: &str| parse::DeclParser::new().parse(src).unwrap();
+    for i in 1..=100 {
+        let action = parse::ExprParser::new()
+            .parse(&format!("action {{ x = {}; y = {} }}", i, i))
+            .unwrap();
+        let (ran, deleted) = tokio::join!(user.run_action(*action), dev.delete("q", false));
+        ran.unwrap();
+        deleted.unwrap();
+        /* The round `q` was part of still completes */
+        tokio::time::timeout(
+            std::time::Duration::from_secs(5),
+            rt.service("s").unwrap().await_quiescent(),
+        )
+        .await
+        .unwrap();
+        dev.declare(decl("def q = p")).await.unwrap();
+    }
+    assert_eq!(rt.read("s", "q").await, Ok(Some(Val::Int(200))));
+}
+
+#[tokio::test]
+async fn crashed_workers_restart_from_their_last_value() {
+    let rt =
+        Runtime::load("service s { var x = 4 var y = 1 / 0 def q = 12 / x pub def r = q + 1 }")
+            .await
+            .unwrap();
+    let mut dev = rt.open_session("s", Role::Developer).await.unwrap();
+    let mut r = rt.subscribe("s", "r").await.unwrap();
+    assert_eq!(r.recv().await.map(|(val, _)| val), Some(Val::Int(4)));
+    /* `y` died evaluating its declaration and had no value yet */
+    assert_eq!(rt.read("s", "y").await, Ok(None));
+    rt.run("s", "action { y = 2 }").await.unwrap();
+    assert_eq!(rt.read("s", "y").await, Ok(Some(Val::Int(2))));
+
+    rt.run("s", "action { x = 0 }").await.unwrap();
+    assert!(matches!(
+        dev.try_notification(),
+        Some(Notification::WorkerRestarted { name, reason })
+            if name == "q" && reason.contains("divide by zero")
+    ));
+    /* `q` keeps its successors and subscribers */
+    rt.run("s", "action { x = 6 }").await.unwrap();
+    assert_eq!(rt.read("s", "q").await, Ok(Some(Val::Int(2))));
+    assert_eq!(r.recv().await.map(|(val, _)| val), Some(Val::Int(3)));
+}
+
+#[tokio::test]
+async fn shutdown_settles_and_refuses_new_work() {
+    let rt = Runtime::load("service s { var x = 1 pub def y = x * 2 }")
+        .await
+        .unwrap();
+    let mut y = rt.subscribe("s", "y").await.unwrap();
+    let user = rt.open_session("s", Role::User).await.unwrap();
+    let action = parse::ExprParser::new().parse("action { x = 5 }").unwrap();
+    let (ran, _) = tokio::join!(user.run_action(*action.clone()), rt.shutdown());
+    assert!(ran.is_ok() || ran.is_err_and(|reason| reason.contains("shutting down")));
+
+    /* The workers have returned, which ends every subscription */
+    while y.recv().await.is_some() {}
+    assert!(user.run_action(*action).await.is_err());
+    assert!(rt.read("s", "x").await.is_err());
+}
+
+#[tokio::test]
+async fn shutdown_joins_pooled_and_restarted_workers() {
+    for pool in [Some(Pool::new(2)), None] {
+        let src = "service s { var x = 1 def y = 2 / x }";
+        let rt = match pool {
+            Some(pool) => Runtime::load_pooled(src, pool).await,
+            None => Runtime::load(src).await,
+        }
+        .unwrap();
+        rt.run("s", "action { x = 0 }").await.unwrap();
+        tokio::time::timeout(std::time::Duration::from_secs(5), rt.shutdown())
+            .await
+            .unwrap();
+    }
+}
+
+/* A pooled worker that panics is restarted on its shard, and the other
+ * nodes there go on */
+#[tokio::test]
+async fn crashed_pooled_workers_restart_without_their_shard() {
+    let rt = Runtime::load_pooled(
+        "service s { var x = 1 def y = 2 / x def z = x + 1 }",
+        Pool::new(1),
+    )
+    .await
+    .unwrap();
+    let mut dev = rt.open_session("s", Role::Developer).await.unwrap();
+    tokio::time::timeout(
+        std::time::Duration::from_secs(5),
+        rt.run("s", "action { x = 0 }"),
+    )
+    .await
+    .unwrap()
+    .unwrap();
+    assert!(matches!(
+        dev.try_notification(),
+        Some(Notification::WorkerRestarted { name, .. }) if name == "y"
+    ));
+    assert_eq!(rt.read("s", "z").await, Ok(Some(Val::Int(1))));
+    assert_eq!(rt.read("s", "y").await, Ok(Some(Val::Int(2))));
+    rt.run("s", "action { x = 2 }").await.unwrap();
+    assert_eq!(rt.read("s", "y").await, Ok(Some(Val::Int(1))));
+    assert_eq!(rt.read("s", "z").await, Ok(Some(Val::Int(3))));
+}
+
+#[tokio::test]
+async fn restore_picks_up_saved_vars_and_code() {
+    let path = std::env::temp_dir().join(format!("restore-{}.json", std::process::id()));
+    let rt = Runtime::load_persisted(COUNTER, &path).await.unwrap();
+    rt.run("counter", "add(5)").await.unwrap();
+    let dev = rt.open_session("counter", Role::Developer).await.unwrap();
+    let decl = |src: &str| parse::DeclParser::new().parse(src).unwrap();
+    dev.update(decl("pub def count = _count * 10"))
+        .await
+        .unwrap();
+    rt.shutdown().await;
+
+    /* Vars start from their saved values and defs from their updated code */
+    let rt = Runtime::restore(&path).await.unwrap();
+    assert_eq!(rt.read("counter", "count").await, Ok(Some(Val::Int(60))));
+    rt.run("counter", "add(1)").await.unwrap();
+    let counter = rt.service("counter").unwrap();
+    let (_, count_version) = counter.read("_count").await.unwrap();
+    let (_, def_version) = counter.read("count").await.unwrap();
+    rt.shutdown().await;
+
+    /* And carry on from the versions they were saved with */
+    let rt = Runtime::restore(&path).await.unwrap();
+    assert_eq!(rt.read("counter", "count").await, Ok(Some(Val::Int(70))));
+    let counter = rt.service("counter").unwrap();
+    assert_eq!(counter.read("_count").await.unwrap().1, count_version);
+    assert_eq!(
+        counter.read("count").await.unwrap().1.code,
+        def_version.code
+    );
+    rt.shutdown().await;
+    let _ = std::fs::remove_file(&path);
+
+    assert!(Runtime::restore(&path).await.is_err());
+}
+
+#[tokio::test]
+async fn queued_actions_are_rechecked_after_code_updates() {
+    let mut manager = ServiceManager::new();
+    let decl = |src: &str| parse::DeclParser::new().parse(src).unwrap();
+    let action = || {
+        *parse::ExprParser::new()
+            .parse("action { x = n + 1 }")
+            .unwrap()
+    };
+    manager.declare(decl("var x = 1")).await.unwrap();
+    manager.declare(decl("def n = 1")).await.unwrap();
+
+    /* Nothing it refers to changed */
+    let queued = manager.queue_action(action());
+    assert_eq!(manager.dequeue_action(queued), Ok(action()));
+
+    /* Changed, but still of the same type */
+    let queued = manager.queue_action(action());
+    manager.update(decl("def n = 2")).await.unwrap();
+    assert_eq!(manager.dequeue_action(queued), Ok(action()));
+
+    /* `n + 1` no longer typechecks */
+    let queued = manager.queue_action(action());
+    manager.update(decl("def n = true")).await.unwrap();
+    assert!(manager
+        .dequeue_action(queued)
+        .is_err_and(|reason| reason.contains("after code update")));
+}
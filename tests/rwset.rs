@@ -0,0 +1,103 @@
+use distr_intrp::backend::rwset::{action_rw_set, RwSet};
+use distr_intrp::frontend::parse;
+use std::collections::{HashMap, HashSet};
+
+fn names(names: &[&str]) -> HashSet<String> {
+    names.iter().map(|name| name.to_string()).collect()
+}
+
+/* The read/write set of `action` in a service with `vars` and `defs`, given
+ * as `(name, source)` pairs */
+fn rw_set(action: &str, vars: &[&str], defs: &[(&str, &str)]) -> Result<RwSet, String> {
+    let defs: HashMap<String, _> = defs
+        .iter()
+        .map(|(name, src)| {
+            (
+                name.to_string(),
+                *parse::ExprParser::new().parse(src).unwrap(),
+            )
+        })
+        .collect();
+    let action = parse::ExprParser::new().parse(action).unwrap();
+    action_rw_set(&action, &names(vars), &defs)
+}
+
+fn expect(reads: &[&str], writes: &[&str]) -> Result<RwSet, String> {
+    Ok(RwSet {
+        reads: names(reads),
+        writes: names(writes),
+    })
+}
+
+#[test]
+fn do_follows_lambda_defs() {
+    let defs = [("add", "fn n => action { count = count + n }")];
+    assert_eq!(
+        rw_set("action { do add(1) }", &["count", "other"], &defs),
+        expect(&["count"], &["count"])
+    );
+    /* The argument is read as well */
+    assert_eq!(
+        rw_set("action { do add(other) }", &["count", "other"], &defs),
+        expect(&["count", "other"], &["count"])
+    );
+}
+
+#[test]
+fn do_follows_both_branches_of_if() {
+    let defs = [
+        ("inc", "action { x = x + 1 }"),
+        ("reset", "action { y = 0 }"),
+    ];
+    assert_eq!(
+        rw_set(
+            "action { do if flag then inc else reset }",
+            &["flag", "x", "y"],
+            &defs
+        ),
+        expect(&["flag", "x"], &["x", "y"])
+    );
+}
+
+#[test]
+fn recursive_action_defs_are_followed_once() {
+    let defs = [("loop", "action { x = x + 1; do loop }")];
+    assert_eq!(
+        rw_set("action { do loop }", &["x"], &defs),
+        expect(&["x"], &["x"])
+    );
+    let defs = [("countdown", "fn n => action { x = n; do countdown(n - 1) }")];
+    assert_eq!(
+        rw_set("action { do countdown(y) }", &["x", "y"], &defs),
+        expect(&["y"], &["x"])
+    );
+}
+
+#[test]
+fn reading_a_def_reads_its_vars() {
+    let defs = [("total", "a + b"), ("double", "total * 2")];
+    assert_eq!(
+        rw_set("action { c = double }", &["a", "b", "c"], &defs),
+        expect(&["a", "b"], &["c"])
+    );
+}
+
+#[test]
+fn parameters_shadow_service_names() {
+    let defs = [("set", "fn x => action { y = x }")];
+    assert_eq!(
+        rw_set("action { do set(1) }", &["x", "y"], &defs),
+        expect(&[], &["y"])
+    );
+    assert_eq!(
+        rw_set("action { y = (fn x => x + 1)(2) }", &["x", "y"], &[]),
+        expect(&[], &["y"])
+    );
+}
+
+#[test]
+fn writes_to_non_vars_are_rejected() {
+    let defs = [("d", "1")];
+    assert!(rw_set("action { d = 2 }", &["x"], &defs).is_err());
+    assert!(rw_set("action { do d }", &["x"], &defs).is_err());
+}
@@ -0,0 +1,85 @@
+use distr_intrp::backend::pool::Pool;
+use distr_intrp::backend::quiescence::InFlight;
+use distr_intrp::{Runtime, Val};
+use std::sync::Arc;
+use std::time::Duration;
+
+const WIDTH: i32 = 32;
+const WRITES: i32 = 2000;
+
+/// `x` fans out to `WIDTH` defs that all fan back in to `sum`, which is
+/// `WIDTH * x` plus a constant whenever it is glitch free.
+fn diamond() -> String {
+    let mut src = String::from("service s { var x = 0 ");
+    let mut terms = vec![];
+    for i in 0..WIDTH {
+        src.push_str(&format!("def m{} = x + {} ", i, i));
+        terms.push(format!("m{}", i));
+    }
+    src.push_str(&format!("pub def sum = {} }}", terms.join(" + ")));
+    src
+}
+
+async fn write_burst(rt: Runtime) {
+    let base = WIDTH * (WIDTH - 1) / 2;
+    let mut sums = rt.subscribe("s", "sum").await.unwrap();
+    /* Intermediate values may be skipped, but each one seen is consistent */
+    let watcher = tokio::spawn(async move {
+        let mut last = -1;
+        while last < WRITES {
+            let x = match sums.recv().await {
+                Some((Val::Int(sum), _)) if (sum - base) % WIDTH == 0 => (sum - base) / WIDTH,
+                sum => panic!("glitch: {:?}", sum),
+            };
+            assert!(x > last);
+            last = x;
+        }
+    });
+    for i in 1..=WRITES {
+        rt.run("s", &format!("action {{ x = {} }}", i))
+            .await
+            .unwrap();
+    }
+    rt.service("s").unwrap().await_quiescent().await;
+    assert_eq!(
+        rt.read("s", "sum").await,
+        Ok(Some(Val::Int(WIDTH * WRITES + base)))
+    );
+    watcher.await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn wide_diamond_survives_write_bursts() {
+    let rt = Runtime::load(&diamond()).await.unwrap();
+    tokio::time::timeout(Duration::from_secs(60), write_burst(rt))
+        .await
+        .unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn pooled_wide_diamond_survives_write_bursts() {
+    let rt = Runtime::load_pooled(&diamond(), Pool::new(2))
+        .await
+        .unwrap();
+    tokio::time::timeout(Duration::from_secs(60), write_burst(rt))
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn new_rounds_wait_for_credit() {
+    let in_flight = Arc::new(InFlight::new());
+    in_flight.start(3);
+    let waiting = tokio::spawn({
+        let in_flight = in_flight.clone();
+        async move { in_flight.below(2).await }
+    });
+    in_flight.done();
+    tokio::time::sleep(Duration::from_millis(20)).await;
+    assert!(!waiting.is_finished());
+    in_flight.done();
+    tokio::time::timeout(Duration::from_secs(5), waiting)
+        .await
+        .unwrap()
+        .unwrap();
+}
@@ -0,0 +1,84 @@
+use distr_intrp::backend::message::Version;
+use distr_intrp::backend::wire::{self, Encoding, WireMessage, WIRE_VERSION};
+use distr_intrp::frontend::meerast::{Decl, Expr};
+use distr_intrp::frontend::parse;
+use distr_intrp::frontend::typecheck::Type;
+use distr_intrp::Val;
+
+fn lambda_update() -> WireMessage {
+    let lambda = parse::ExprParser::new()
+        .parse("fn n => action { _count = _count + n }")
+        .unwrap();
+    WireMessage::PredUpdatedTo {
+        pred_name: String::from("add"),
+        pred_value: Val::Lambda(*lambda),
+        pred_version: Version { code: 2, value: 7 },
+    }
+}
+
+#[test]
+fn messages_round_trip_in_both_encodings() {
+    for encoding in [Encoding::Json, Encoding::Binary] {
+        for msg in [
+            lambda_update(),
+            WireMessage::Subscribe {
+                name: String::from("count"),
+            },
+            WireMessage::Error {
+                reason: String::from("`x` is not a public def"),
+            },
+        ] {
+            let bytes = wire::encode(&msg, encoding).unwrap();
+            assert_eq!(&bytes[..2], &WIRE_VERSION.to_be_bytes());
+            assert_eq!(wire::decode(&bytes), Ok((msg, encoding)));
+        }
+    }
+}
+
+#[test]
+fn binary_is_more_compact_than_json() {
+    let json = wire::encode(&lambda_update(), Encoding::Json).unwrap();
+    let binary = wire::encode(&lambda_update(), Encoding::Binary).unwrap();
+    assert!(binary.len() < json.len());
+}
+
+#[test]
+fn unknown_versions_and_encodings_are_rejected() {
+    let mut bytes = wire::encode(&lambda_update(), Encoding::Binary).unwrap();
+    bytes[2] = 7;
+    assert!(wire::decode(&bytes).is_err());
+    bytes[..2].copy_from_slice(&(WIRE_VERSION + 1).to_be_bytes());
+    assert!(wire::decode(&bytes).is_err());
+    assert!(wire::decode(&[]).is_err());
+}
+
+#[test]
+fn ast_and_types_serialize() {
+    let decl = parse::DeclParser::new()
+        .parse("pub def add = fn n => action { _count = _count + n }")
+        .unwrap();
+    let json = serde_json::to_string(&decl).unwrap();
+    let back: Decl = serde_json::from_str(&json).unwrap();
+    match (decl, back) {
+        (
+            Decl::DefDecl { val: v1, .. },
+            Decl::DefDecl {
+                val: v2, is_pub, ..
+            },
+        ) => {
+            assert_eq!(v1, v2);
+            assert!(is_pub);
+        }
+        _ => panic!("expected a def"),
+    }
+
+    let ty = Type::Fun {
+        par_types: vec![Type::Int],
+        ret_type: Box::new(Type::Action),
+    };
+    let bytes = bincode::serialize(&ty).unwrap();
+    assert_eq!(bincode::deserialize::<Type>(&bytes).unwrap(), ty);
+    let expr: Expr =
+        serde_json::from_str(&serde_json::to_string(&Expr::IntConst { val: 3 }).unwrap()).unwrap();
+    assert_eq!(expr, Expr::IntConst { val: 3 });
+}
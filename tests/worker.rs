@@ -0,0 +1,333 @@
+use distr_intrp::backend::defworker_proc::DefWorker;
+use distr_intrp::backend::message::{Message, Round, Val};
+use distr_intrp::backend::metrics::Metrics;
+use distr_intrp::backend::quiescence::InFlight;
+use distr_intrp::backend::varworker_proc::VarWorker;
+use distr_intrp::backend::worker::{Addr, Remains, Subscriber, Worker, WorkerCore};
+use distr_intrp::frontend::parse;
+use std::sync::Arc;
+use tokio::sync::{mpsc, oneshot};
+
+fn round(id: u64, affected: &[&str]) -> Round {
+    Round {
+        id,
+        affected: Arc::new(affected.iter().map(|name| name.to_string()).collect()),
+    }
+}
+
+async fn apprise(worker: &mut impl Worker) -> (Option<Val>, Option<String>) {
+    let (reply_to, reply) = oneshot::channel();
+    worker
+        .handle_message(Message::RetrieveVal { reply_to })
+        .await;
+    match reply.await.unwrap() {
+        Message::AppriseVal {
+            worker_value,
+            pending,
+            ..
+        } => (worker_value, pending),
+        msg => panic!("unexpected reply {:?}", msg),
+    }
+}
+
+#[tokio::test]
+async fn defs_stay_pending_until_every_input_arrives() {
+    let (_inbox_sndr, inbox) = mpsc::unbounded_channel();
+    let (to_manager, _from_workers) = mpsc::channel(16);
+    let in_flight = Arc::new(InFlight::new());
+    in_flight.start(3);
+    let mut c = DefWorker::new(WorkerCore::new(
+        inbox,
+        to_manager,
+        in_flight.clone(),
+        Arc::new(Metrics::new()),
+        "c",
+    ));
+    assert_eq!(
+        apprise(&mut c).await,
+        (None, Some(String::from("not initialised yet")))
+    );
+
+    let def_expr = *parse::ExprParser::new().parse("a + b").unwrap();
+    c.handle_message(Message::InitDef {
+        def_name: String::from("c"),
+        def_expr,
+        code_version: 1,
+        lazy: false,
+        round: round(0, &["c"]),
+    })
+    .await;
+    assert_eq!(
+        apprise(&mut c).await,
+        (None, Some(String::from("waiting for `a`, `b`")))
+    );
+
+    for (id, pred, val) in [(1, "a", 1), (2, "b", 2)] {
+        c.handle_message(Message::PredUpdatedTo {
+            pred_name: pred.to_string(),
+            pred_value: Some(Val::Int(val)),
+            pred_version: Default::default(),
+            superseded: false,
+            round: round(id, &[pred, "c"]),
+        })
+        .await;
+    }
+    assert_eq!(apprise(&mut c).await, (Some(Val::Int(3)), None));
+    /* Computed once, when `b` arrived */
+    assert_eq!(c.core.version.value, 1);
+    in_flight.quiescent().await;
+}
+
+/* Every value `c` takes: it must never mix a fresh `a` with a stale `b`
+ * when both change in the same round */
+#[tokio::test]
+async fn defs_wait_for_every_input_changed_in_a_round() {
+    let (_inbox_sndr, inbox) = mpsc::unbounded_channel();
+    let (to_manager, _from_workers) = mpsc::channel(16);
+    let in_flight = Arc::new(InFlight::new());
+    in_flight.start(4);
+    let mut c = DefWorker::new(WorkerCore::new(
+        inbox,
+        to_manager,
+        in_flight.clone(),
+        Arc::new(Metrics::new()),
+        "c",
+    ));
+    let (client, mut history) = mpsc::channel(16);
+    c.handle_message(Message::Subscribe { sender: client })
+        .await;
+    c.handle_message(Message::InitDef {
+        def_name: String::from("c"),
+        def_expr: *parse::ExprParser::new().parse("a + b").unwrap(),
+        code_version: 1,
+        lazy: false,
+        round: round(0, &["c"]),
+    })
+    .await;
+    let updates = [
+        (1, "a", 1),
+        (1, "b", 2),
+        (2, "a", 10),
+        (2, "b", 20),
+        (3, "b", 30),
+    ];
+    for (id, pred, val) in updates {
+        let affected: &[&str] = match id {
+            3 => &["b", "c"],
+            _ => &["a", "b", "c"],
+        };
+        c.handle_message(Message::PredUpdatedTo {
+            pred_name: pred.to_string(),
+            pred_value: Some(Val::Int(val)),
+            pred_version: Default::default(),
+            superseded: false,
+            round: round(id, affected),
+        })
+        .await;
+    }
+    let mut seen = vec![];
+    while let Ok((val, _)) = history.try_recv() {
+        seen.push(val);
+    }
+    assert_eq!(seen, vec![Val::Int(3), Val::Int(30), Val::Int(40)]);
+    in_flight.quiescent().await;
+}
+
+#[tokio::test]
+async fn misdirected_messages_are_rejected() {
+    let (_inbox_sndr, inbox) = mpsc::unbounded_channel();
+    let (to_manager, mut from_workers) = mpsc::channel(16);
+    let in_flight = Arc::new(InFlight::new());
+    let mut x = VarWorker::new(WorkerCore::new(
+        inbox,
+        to_manager,
+        in_flight.clone(),
+        Arc::new(Metrics::new()),
+        "x",
+    ));
+    in_flight.start(2);
+    x.handle_message(Message::InitVar {
+        var_name: String::from("x"),
+        var_expr: *parse::ExprParser::new().parse("1").unwrap(),
+        code_version: 1,
+        value_version: None,
+        round: round(0, &["x"]),
+    })
+    .await;
+    x.handle_message(Message::PredUpdatedTo {
+        pred_name: String::from("y"),
+        pred_value: Some(Val::Int(2)),
+        pred_version: Default::default(),
+        round: round(1, &["y", "x"]),
+        superseded: false,
+    })
+    .await;
+    assert!(matches!(
+        from_workers.recv().await,
+        Some(Message::Rejected { worker_name, .. }) if worker_name == "x"
+    ));
+    assert_eq!(apprise(&mut x).await, (Some(Val::Int(1)), None));
+    /* The rejected round does not hold up quiescence */
+    in_flight.quiescent().await;
+}
+
+#[tokio::test]
+async fn defs_only_follow_the_inputs_they_read() {
+    let (_inbox_sndr, inbox) = mpsc::unbounded_channel();
+    let (to_manager, _from_workers) = mpsc::channel(16);
+    let in_flight = Arc::new(InFlight::new());
+    let metrics = Arc::new(Metrics::new());
+    in_flight.start(4);
+    let mut d = DefWorker::new(WorkerCore::new(
+        inbox,
+        to_manager,
+        in_flight.clone(),
+        metrics.clone(),
+        "d",
+    ));
+    d.handle_message(Message::InitDef {
+        def_name: String::from("d"),
+        def_expr: *parse::ExprParser::new()
+            .parse("if c then a else b")
+            .unwrap(),
+        code_version: 1,
+        lazy: false,
+        round: round(0, &["d"]),
+    })
+    .await;
+    let updates = [
+        (1, "c", Val::Bool(true)),
+        (1, "a", Val::Int(1)),
+        (1, "b", Val::Int(2)),
+        (2, "b", Val::Int(5)),
+        (3, "c", Val::Bool(false)),
+    ];
+    for (id, pred, val) in updates {
+        let affected: &[&str] = match id {
+            1 => &["a", "b", "c", "d"],
+            2 => &["b", "d"],
+            _ => &["c", "d"],
+        };
+        d.handle_message(Message::PredUpdatedTo {
+            pred_name: pred.to_string(),
+            pred_value: Some(val),
+            pred_version: Default::default(),
+            superseded: false,
+            round: round(id, affected),
+        })
+        .await;
+        if id == 2 {
+            /* `b` is not read while `c` holds, so nothing is recomputed */
+            assert_eq!(
+                d.last_read,
+                ["a", "c"].iter().map(|name| name.to_string()).collect()
+            );
+            assert_eq!(apprise(&mut d).await, (Some(Val::Int(1)), None));
+            assert_eq!(metrics.snapshot().recomputes, 1);
+            assert_eq!(metrics.snapshot().unchanged, 1);
+        }
+    }
+    assert_eq!(apprise(&mut d).await, (Some(Val::Int(5)), None));
+    assert_eq!(metrics.snapshot().recomputes, 2);
+    in_flight.quiescent().await;
+}
+
+#[tokio::test]
+async fn bursts_of_rounds_are_computed_once() {
+    let (inbox_sndr, inbox) = mpsc::unbounded_channel();
+    let (to_manager, _from_workers) = mpsc::channel(16);
+    let in_flight = Arc::new(InFlight::new());
+    let metrics = Arc::new(Metrics::new());
+    in_flight.start(6);
+    let mut c = DefWorker::new(WorkerCore::new(
+        inbox,
+        to_manager,
+        in_flight.clone(),
+        metrics.clone(),
+        "c",
+    ));
+    let (succ, mut succ_inbox) = mpsc::unbounded_channel();
+    c.core
+        .senders_to_succs
+        .push(Subscriber::Succ(Addr::Task(succ), Default::default()));
+    c.handle_message(Message::InitDef {
+        def_name: String::from("c"),
+        def_expr: *parse::ExprParser::new().parse("x * 10").unwrap(),
+        code_version: 1,
+        lazy: false,
+        round: round(0, &["c"]),
+    })
+    .await;
+
+    /* Five writes of `x` queue up before `c` gets to run */
+    let report = |id: u64| Message::PredUpdatedTo {
+        pred_name: String::from("x"),
+        pred_value: Some(Val::Int(id as i32)),
+        pred_version: Default::default(),
+        round: round(id, &["x", "c", "d"]),
+        superseded: false,
+    };
+    for id in 2..=5 {
+        inbox_sndr.send(report(id)).unwrap();
+    }
+    c.handle_message(report(1)).await;
+    assert_eq!(apprise(&mut c).await, (Some(Val::Int(50)), None));
+    assert_eq!(metrics.snapshot().recomputes, 1);
+    assert_eq!(metrics.snapshot().coalesced, 4);
+
+    /* The successor hears about every round, only the last one fresh */
+    assert!(matches!(
+        succ_inbox.try_recv(),
+        Ok(Message::PredUpdatedTo { round, .. }) if round.id == 0
+    ));
+    let mut heard = vec![];
+    while let Ok(Message::PredUpdatedTo {
+        pred_value,
+        superseded,
+        ..
+    }) = succ_inbox.try_recv()
+    {
+        heard.push((pred_value, superseded));
+    }
+    let mut expected = vec![(None, true); 4];
+    expected.push((Some(Val::Int(50)), false));
+    assert_eq!(heard, expected);
+    in_flight.quiescent().await;
+}
+
+#[tokio::test]
+async fn restarted_defs_are_pending_until_they_compute_again() {
+    let (_inbox_sndr, inbox) = mpsc::unbounded_channel();
+    let (to_manager, _from_workers) = mpsc::channel(16);
+    let in_flight = Arc::new(InFlight::new());
+    in_flight.start(1);
+    let core = WorkerCore::new(
+        inbox,
+        to_manager,
+        in_flight.clone(),
+        Arc::new(Metrics::new()),
+        "q",
+    );
+    /* What a `q` that died computing `12 / 0` left */
+    let mut remains = Remains {
+        curr_val: Some(Val::Int(3)),
+        replica: [(String::from("x"), Val::Int(4))].into_iter().collect(),
+        last_read: [String::from("x")].into_iter().collect(),
+        ..Default::default()
+    };
+    let def_expr = *parse::ExprParser::new().parse("12 / x").unwrap();
+    let mut q = DefWorker::restore(core, def_expr, false, &mut remains);
+    let restarted = Some(String::from("restarted, computed once an input changes"));
+    assert_eq!(apprise(&mut q).await.1, restarted);
+
+    q.handle_message(Message::PredUpdatedTo {
+        pred_name: String::from("x"),
+        pred_value: Some(Val::Int(6)),
+        pred_version: Default::default(),
+        superseded: false,
+        round: round(1, &["x", "q"]),
+    })
+    .await;
+    assert_eq!(apprise(&mut q).await, (Some(Val::Int(2)), None));
+    in_flight.quiescent().await;
+}